@@ -0,0 +1,35 @@
+// The kernel command line, taken from /chosen/bootargs in the device tree
+// (e.g. QEMU's -append).  It is a space separated list of `key=value` or
+// `key` words.
+
+use crate::spinlock::RwSpinlock;
+
+static CMDLINE: RwSpinlock<&'static str> = RwSpinlock::new("");
+
+pub fn init() {
+    let bootargs = crate::devicetree::get()
+        .and_then(|dt| dt.find("/chosen"))
+        .and_then(|chosen| chosen.property_str("bootargs"));
+    if let Some(bootargs) = bootargs {
+        *CMDLINE.write() = bootargs;
+    }
+}
+
+/// The whole command line
+#[allow(dead_code)]
+pub fn as_str() -> &'static str {
+    *CMDLINE.read()
+}
+
+/// The value of `key=value` on the command line.  A bare `key` gives "".
+#[allow(dead_code)]
+pub fn get(key: &str) -> Option<&'static str> {
+    let cmdline: &'static str = *CMDLINE.read();
+    for word in cmdline.split_whitespace() {
+        let mut parts = word.splitn(2, '=');
+        if parts.next() == Some(key) {
+            return Some(parts.next().unwrap_or(""));
+        }
+    }
+    None
+}
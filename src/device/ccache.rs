@@ -0,0 +1,126 @@
+// The SiFive L2 cache controller ("ccache"), documented in
+// fu740-c000-manual-v1p2.pdf chapter 13.
+//
+// Ways that are not enabled as cache are instead usable as plain memory at
+// the LIM (loosely-integrated memory) address.  Ways can only be enabled,
+// never disabled again (short of a reset), and the LIM shrinks from the top
+// as they are.
+
+use crate::register::{AtomicRegisterU32RO, AtomicRegisterU32RW,
+                      AtomicRegisterU64RW, AtomicRegisterU64WO};
+
+pub struct SifiveCcache {
+    base_address: usize,
+    lim_address: usize,
+    lim_size: usize,
+}
+
+#[allow(dead_code)] // this device may not be present on the target machine
+impl SifiveCcache {
+    #[inline(always)]
+    pub const unsafe fn new(base_address: usize, lim_address: usize, lim_size: usize) -> Self {
+        SifiveCcache {
+            base_address: base_address,
+            lim_address: lim_address,
+            lim_size: lim_size,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn config(&self) -> AtomicRegisterU32RO {
+        AtomicRegisterU32RO::new(self.base_address)
+    }
+
+    #[inline(always)]
+    unsafe fn way_enable(&self) -> AtomicRegisterU32RW {
+        AtomicRegisterU32RW::new(self.base_address + 0x008)
+    }
+
+    #[inline(always)]
+    unsafe fn flush64(&self) -> AtomicRegisterU64WO {
+        AtomicRegisterU64WO::new(self.base_address + 0x200)
+    }
+
+    #[inline(always)]
+    unsafe fn way_mask(&self, master: usize) -> AtomicRegisterU64RW {
+        AtomicRegisterU64RW::new(self.base_address + 0x800 + 8 * master)
+    }
+
+    /// Whether the controller is there.  QEMU's sifive_u models the LIM but
+    /// not the controller, whose registers then read as zero.
+    pub fn is_present(&self) -> bool {
+        unsafe { self.config().fetch() != 0 }
+    }
+
+    pub fn banks(&self) -> usize {
+        unsafe { self.config().get_bits(0..=7) as usize }
+    }
+
+    pub fn ways(&self) -> usize {
+        unsafe { self.config().get_bits(8..=15) as usize }
+    }
+
+    /// Sets per bank
+    pub fn sets(&self) -> usize {
+        1 << unsafe { self.config().get_bits(16..=23) }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1 << unsafe { self.config().get_bits(24..=31) }
+    }
+
+    /// Bytes of cache (or LIM) per way
+    pub fn way_size(&self) -> usize {
+        self.banks() * self.sets() * self.block_size()
+    }
+
+    /// The number of ways enabled as cache (at least one always is)
+    pub fn enabled_ways(&self) -> usize {
+        if ! self.is_present() {
+            return 0;
+        }
+        unsafe { self.way_enable().fetch() as usize + 1 }
+    }
+
+    /// Enable ways as cache, up to `ways` in total.  This takes memory away
+    /// from the top of the LIM, which must not be in use.  It cannot be
+    /// undone.
+    pub unsafe fn enable_ways(&self, ways: usize) {
+        if self.is_present() && ways > self.enabled_ways() && ways <= self.ways() {
+            self.way_enable().store(ways as u32 - 1);
+        }
+    }
+
+    /// The ways master `master` may allocate into (evict from), one bit per
+    /// way
+    pub fn get_way_mask(&self, master: usize) -> u64 {
+        unsafe { self.way_mask(master).fetch() }
+    }
+
+    /// Restrict which ways master `master` may allocate into (evict from), so
+    /// that the lines other masters keep in the remaining ways stay resident
+    pub unsafe fn set_way_mask(&self, master: usize, mask: u64) {
+        self.way_mask(master).store(mask);
+    }
+
+    /// Write back and invalidate the cache line holding physical address `pa`
+    pub fn flush(&self, pa: usize) {
+        if self.is_present() {
+            unsafe { self.flush64().store(pa as u64) };
+        }
+    }
+
+    /// Physical address of the LIM
+    pub fn lim_address(&self) -> usize {
+        self.lim_address
+    }
+
+    /// Bytes of the LIM presently usable (those in ways not enabled as cache)
+    pub fn lim_size(&self) -> usize {
+        if ! self.is_present() {
+            return self.lim_size;
+        }
+        let free_ways = self.ways().saturating_sub(self.enabled_ways());
+        (free_ways * self.way_size()).min(self.lim_size)
+    }
+}
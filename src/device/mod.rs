@@ -1,2 +1,2 @@
-
+pub mod ccache;
 pub mod uart;
@@ -2,9 +2,8 @@
 pub mod sifive;
 pub mod uart16550;
 
-use core::fmt::Write;
 
-pub trait Uart: Write {
+pub trait Uart {
     fn put(&self, c: u8);
     fn get_maybe(&self) -> Option<u8>;
     fn set_line_settings(&self,
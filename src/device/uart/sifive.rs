@@ -129,7 +129,7 @@ impl Uart for SifiveUart {
     }
 }
 
-impl Write for SifiveUart {
+impl Write for &SifiveUart {
     fn write_str(&mut self, s: &str) -> Result<(), Error> {
         for c in s.bytes() {
             self.put(c);
@@ -11,7 +11,7 @@ pub struct Uart16550 {
 
 impl Uart16550 {
     #[allow(dead_code)]
-    pub const unsafe fn new(base_address: usize) -> Self {
+    pub unsafe fn new(base_address: usize) -> Self {
         Uart16550 {
             inner: Spinlock::new(InnerUart16550::new(base_address))
         }
@@ -49,7 +49,7 @@ impl Uart for Uart16550 {
     }
 }
 
-impl Write for Uart16550 {
+impl Write for &Uart16550 {
     fn write_str(&mut self, s: &str) -> Result<(), Error> {
         for c in s.bytes() {
             self.put(c);
@@ -0,0 +1,353 @@
+// A reader for the flattened device tree (DTB) handed to us at boot.
+// See the Devicetree Specification v0.3, chapter 5.
+//
+// The DTB is never freed or moved, so everything read out of it is 'static.
+
+use crate::memory::phys_to_virt;
+use crate::spinlock::RwSpinlock;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+static DEVICE_TREE: RwSpinlock<Option<DeviceTree>> = RwSpinlock::new(None);
+
+#[derive(Clone, Copy)]
+pub struct DeviceTree {
+    base: usize,      // virtual address of the header
+    size: usize,
+    structs: usize,   // offset of the structure block
+    strings: usize,   // offset of the strings block
+    rsvmap: usize,    // offset of the memory reservation block
+}
+
+#[allow(dead_code)]
+impl DeviceTree {
+    /// Check for a DTB at virtual address `va`.  It must stay mapped and
+    /// unmodified for as long as the kernel runs.
+    pub unsafe fn new(va: usize) -> Option<DeviceTree> {
+        if va == 0 || va % 8 != 0 {
+            return None;
+        }
+        let be32 = |off: usize| u32::from_be(*((va + off) as *const u32)) as usize;
+        if be32(0) as u32 != FDT_MAGIC {
+            return None;
+        }
+        // We understand version 17, and anything compatible with it
+        if be32(24) > 17 {
+            return None;
+        }
+        Some(DeviceTree {
+            base: va,
+            size: be32(4),
+            structs: be32(8),
+            strings: be32(12),
+            rsvmap: be32(16),
+        })
+    }
+
+    /// Virtual address of the DTB
+    pub fn address(&self) -> usize {
+        self.base
+    }
+
+    /// Total size of the DTB in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline(always)]
+    fn be32(&self, off: usize) -> u32 {
+        u32::from_be(unsafe { *((self.base + off) as *const u32) })
+    }
+
+    #[inline(always)]
+    fn be64(&self, off: usize) -> u64 {
+        ((self.be32(off) as u64) << 32) | self.be32(off + 4) as u64
+    }
+
+    // A nul terminated string at offset `off`
+    fn cstr(&self, off: usize) -> &'static str {
+        let start = (self.base + off) as *const u8;
+        let mut len = 0;
+        while off + len < self.size && unsafe { *start.add(len) } != 0 {
+            len += 1;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(start, len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    // Skip from the properties of a node to just past its FDT_END_NODE
+    fn skip_node(&self, mut off: usize) -> usize {
+        let mut depth = 1;
+        while depth > 0 && off < self.size {
+            match self.be32(off) {
+                FDT_BEGIN_NODE => {
+                    depth += 1;
+                    off = align4(off + 4 + self.cstr(off + 4).len() + 1);
+                },
+                FDT_END_NODE => {
+                    depth -= 1;
+                    off += 4;
+                },
+                FDT_PROP => {
+                    off = align4(off + 12 + self.be32(off + 4) as usize);
+                },
+                FDT_NOP => off += 4,
+                _ => return self.size, // FDT_END (9) or garbage
+            }
+        }
+        off
+    }
+
+    /// The root node
+    pub fn root(&self) -> Node {
+        let mut off = self.structs;
+        while self.be32(off) == FDT_NOP {
+            off += 4;
+        }
+        // off is now at the FDT_BEGIN_NODE of the root, which has an empty name
+        Node {
+            dt: *self,
+            name: "",
+            props: align4(off + 4 + self.cstr(off + 4).len() + 1),
+        }
+    }
+
+    /// Find a node by its full path, e.g. "/cpus/cpu@0".  A path component
+    /// without a unit address matches the first node with that name.
+    pub fn find(&self, path: &str) -> Option<Node> {
+        let mut node = self.root();
+        for component in path.split('/').filter(|c| ! c.is_empty()) {
+            node = node.child(component)?;
+        }
+        Some(node)
+    }
+
+    /// Entries of the memory reservation block, as (address, size)
+    pub fn reservations(&self) -> Reservations {
+        Reservations { dt: *self, off: self.rsvmap }
+    }
+}
+
+#[inline(always)]
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// A node of the device tree
+#[derive(Clone, Copy)]
+pub struct Node {
+    dt: DeviceTree,
+    name: &'static str,
+    props: usize,     // offset of the first token after the name
+}
+
+#[allow(dead_code)]
+impl Node {
+    /// The node name, including any unit address (e.g. "memory@80000000")
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The node name without its unit address
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or("")
+    }
+
+    pub fn properties(&self) -> Properties {
+        Properties { dt: self.dt, off: self.props }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        self.properties().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// A string property, without its nul terminator
+    pub fn property_str(&self, name: &str) -> Option<&'static str> {
+        let value = self.property(name)?;
+        let value = match value.split_last() {
+            Some((0, rest)) => rest,
+            _ => value,
+        };
+        core::str::from_utf8(value).ok()
+    }
+
+    /// A property of one big-endian u32 cell
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        let value = self.property(name)?;
+        if value.len() != 4 { return None; }
+        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    /// A property of one or two big-endian u32 cells (e.g.
+    /// linux,initrd-start, which may be either)
+    pub fn property_cells(&self, name: &str) -> Option<u64> {
+        let value = self.property(name)?;
+        match value.len() {
+            4 | 8 => Some(read_cells(value, value.len() / 4)),
+            _ => None,
+        }
+    }
+
+    /// #address-cells of this node, i.e. of the reg properties of its
+    /// children
+    pub fn address_cells(&self) -> usize {
+        self.property_u32("#address-cells").unwrap_or(2) as usize
+    }
+
+    /// #size-cells of this node, i.e. of the reg properties of its children
+    pub fn size_cells(&self) -> usize {
+        self.property_u32("#size-cells").unwrap_or(1) as usize
+    }
+
+    /// The (address, size) entries of the reg property, given the parent
+    /// node's #address-cells and #size-cells
+    pub fn reg(&self, address_cells: usize, size_cells: usize) -> Reg {
+        Reg {
+            value: self.property("reg").unwrap_or(&[]),
+            address_cells: address_cells,
+            size_cells: size_cells,
+        }
+    }
+
+    pub fn children(&self) -> Children {
+        Children { dt: self.dt, off: self.props }
+    }
+
+    /// The first child called `name` (or `name@...` if `name` has no unit
+    /// address)
+    pub fn child(&self, name: &str) -> Option<Node> {
+        if name.contains('@') {
+            self.children().find(|c| c.name == name)
+        } else {
+            self.children().find(|c| c.base_name() == name)
+        }
+    }
+}
+
+// A number made of `cells` big-endian u32 cells (only the low 64 bits kept)
+fn read_cells(bytes: &[u8], cells: usize) -> u64 {
+    bytes[..cells * 4].chunks(4).fold(0, |acc, c| {
+        (acc << 32) | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as u64
+    })
+}
+
+/// Iterator over the entries of a reg property, as (address, size)
+pub struct Reg {
+    value: &'static [u8],
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Iterator for Reg {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let len = (self.address_cells + self.size_cells) * 4;
+        if len == 0 || self.value.len() < len {
+            return None;
+        }
+        let address = read_cells(self.value, self.address_cells);
+        let size = read_cells(&self.value[self.address_cells * 4..], self.size_cells);
+        self.value = &self.value[len..];
+        Some((address, size))
+    }
+}
+
+/// Iterator over the properties of a node, as (name, value)
+pub struct Properties {
+    dt: DeviceTree,
+    off: usize,
+}
+
+impl Iterator for Properties {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.off >= self.dt.size { return None; }
+            match self.dt.be32(self.off) {
+                FDT_NOP => self.off += 4,
+                FDT_PROP => {
+                    let len = self.dt.be32(self.off + 4) as usize;
+                    let name = self.dt.cstr(self.dt.strings + self.dt.be32(self.off + 8) as usize);
+                    let value = unsafe {
+                        core::slice::from_raw_parts((self.dt.base + self.off + 12) as *const u8, len)
+                    };
+                    self.off = align4(self.off + 12 + len);
+                    return Some((name, value));
+                },
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over the children of a node
+pub struct Children {
+    dt: DeviceTree,
+    off: usize,
+}
+
+impl Iterator for Children {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        loop {
+            if self.off >= self.dt.size { return None; }
+            match self.dt.be32(self.off) {
+                FDT_NOP => self.off += 4,
+                FDT_PROP => {
+                    self.off = align4(self.off + 12 + self.dt.be32(self.off + 4) as usize);
+                },
+                FDT_BEGIN_NODE => {
+                    let name = self.dt.cstr(self.off + 4);
+                    let props = align4(self.off + 4 + name.len() + 1);
+                    self.off = self.dt.skip_node(props);
+                    return Some(Node { dt: self.dt, name: name, props: props });
+                },
+                _ => return None, // FDT_END_NODE ends the children
+            }
+        }
+    }
+}
+
+/// Iterator over the memory reservation block
+pub struct Reservations {
+    dt: DeviceTree,
+    off: usize,
+}
+
+impl Iterator for Reservations {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let address = self.dt.be64(self.off);
+        let size = self.dt.be64(self.off + 8);
+        if address == 0 && size == 0 {
+            return None;
+        }
+        self.off += 16;
+        Some((address, size))
+    }
+}
+
+/// Keep the DTB found at physical address `pa` (if there is one there).  It
+/// must be reachable through the direct map.
+pub fn init(pa: usize) -> Option<DeviceTree> {
+    if pa == 0 {
+        return None;
+    }
+    let dt = unsafe { DeviceTree::new(phys_to_virt(pa)) }?;
+    *DEVICE_TREE.write() = Some(dt);
+    Some(dt)
+}
+
+/// The device tree, if the kernel was given one
+#[allow(dead_code)]
+pub fn get() -> Option<DeviceTree> {
+    *DEVICE_TREE.read()
+}
@@ -0,0 +1,133 @@
+// Buffers for devices to DMA to and from: physically contiguous, with a known
+// bus address (the physical address, as we have no IOMMU).
+//
+// A coherent buffer can be shared by the CPU and a device at any time.  On a
+// machine where devices don't see the CPU caches it is made non-cacheable
+// (Svpbmt NC), so it needs Svpbmt there.
+//
+// A streaming buffer is cached, and is handed between the CPU and the device
+// with sync_for_device() and sync_for_cpu(), which do the cache maintenance
+// (Zicbom) non-coherent machines need, and nothing on coherent ones.  A
+// non-coherent machine without Zicbom can't have streaming buffers at all.
+
+use crate::memory::{frame, phys_to_virt, PAGE_SIZE, page_round_up};
+use crate::target::{cache, DMA_COHERENT};
+use crate::target::paging::{pbmt, MemoryType};
+use crate::target::paging::kernel::set_direct_map_type;
+
+/// Which way data moves in a streaming transfer
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DmaDirection {
+    ToDevice,
+    FromDevice,
+    Bidirectional,
+}
+
+pub struct DmaBuffer {
+    pa: usize,
+    size: usize,
+    frames: usize,
+    uncached: bool,     // made NC in the direct map
+}
+
+#[allow(dead_code)]
+impl DmaBuffer {
+    // Allocate zeroed, contiguous frames aligned to `align` bytes
+    fn alloc(size: usize, align: usize) -> Option<DmaBuffer> {
+        let frames = page_round_up(size.max(1)) / PAGE_SIZE;
+        let pa = frame::alloc_frames(frames, (align / PAGE_SIZE).max(1))?;
+        unsafe { core::ptr::write_bytes(phys_to_virt(pa) as *mut u8, 0, frames * PAGE_SIZE) };
+        Some(DmaBuffer { pa: pa, size: size, frames: frames, uncached: false })
+    }
+
+    /// A buffer of `size` bytes that the CPU and a device may share at any
+    /// time.  None if there is no memory, or if the machine is not coherent
+    /// and has no Svpbmt to make it non-cacheable.
+    pub fn new_coherent(size: usize, align: usize) -> Option<DmaBuffer> {
+        if ! DMA_COHERENT && ! pbmt::available() {
+            return None;
+        }
+        let mut buf = DmaBuffer::alloc(size, align)?;
+        if ! DMA_COHERENT {
+            // Nothing of the zeroing may be left in the cache to be written
+            // back over what the device writes
+            cache::flush(buf.as_ptr() as usize, buf.frames * PAGE_SIZE);
+            set_direct_map_type(buf.pa, buf.frames * PAGE_SIZE, MemoryType::Nc).ok()?;
+            buf.uncached = true;
+        }
+        Some(buf)
+    }
+
+    /// A cached buffer of `size` bytes, to be passed between the CPU and a
+    /// device with `sync_for_device()` and `sync_for_cpu()`.  None if there
+    /// is no memory, or if the machine is not coherent and has no Zicbom to
+    /// do the syncing.
+    pub fn new_streaming(size: usize, align: usize) -> Option<DmaBuffer> {
+        if ! DMA_COHERENT && ! cache::available() {
+            println!("DMA: no streaming buffers without Zicbom on a non-coherent machine");
+            return None;
+        }
+        DmaBuffer::alloc(size, align)
+    }
+
+    /// The address the device uses
+    pub fn bus_address(&self) -> usize {
+        self.pa
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.pa) as *mut u8
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+
+    /// Hand the buffer to the device, before it starts a transfer
+    pub fn sync_for_device(&self, dir: DmaDirection) {
+        if DMA_COHERENT || self.uncached {
+            return;
+        }
+        match dir {
+            DmaDirection::ToDevice | DmaDirection::Bidirectional => {
+                cache::clean(self.as_ptr() as usize, self.size);
+            },
+            DmaDirection::FromDevice => {
+                // Dirty lines written back later would overwrite the transfer
+                cache::flush(self.as_ptr() as usize, self.size);
+            },
+        }
+    }
+
+    /// Take the buffer back from the device, after a transfer has finished
+    pub fn sync_for_cpu(&self, dir: DmaDirection) {
+        if DMA_COHERENT || self.uncached {
+            return;
+        }
+        match dir {
+            DmaDirection::FromDevice | DmaDirection::Bidirectional => {
+                // Lines may have been speculatively fetched during the transfer
+                cache::invalidate(self.as_ptr() as usize, self.size);
+            },
+            DmaDirection::ToDevice => { },
+        }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.uncached {
+            let _ = set_direct_map_type(self.pa, self.frames * PAGE_SIZE, MemoryType::Pma);
+        }
+        frame::free_frames(self.pa, self.frames);
+    }
+}
@@ -0,0 +1,143 @@
+// Harts and per-hart data.
+//
+// Hart ids can be sparse (the Unmatched's harts 1-4 run the kernel, hart 0 is
+// the S7 monitor core), so each hart that runs the kernel also gets a dense
+// index, 0 for the boot hart and then in device tree order.  A hart's thread
+// pointer (tp) points at its HartArea, holding both, so finding them is a
+// load rather than a CSR read (mhartid is machine mode only anyway).  This
+// works the same for machine and supervisor mode kernels.  trap.S reloads tp
+// from the TrapScratch on every trap, so it can be trusted in trap handlers
+// whatever the interrupted code did with it.
+
+use crate::atomic::{Atomic, AtomicUSize};
+use crate::target::MAX_HARTS;
+
+/// What a hart's tp points to
+#[repr(C)]
+pub struct HartArea {
+    index: AtomicUSize,
+    hart_id: AtomicUSize,
+}
+
+const HART_AREA_INIT: HartArea = HartArea {
+    index: AtomicUSize::new(0),
+    hart_id: AtomicUSize::new(0),
+};
+
+// By dense index
+static AREAS: [HartArea; MAX_HARTS] = [HART_AREA_INIT; MAX_HARTS];
+static COUNT: AtomicUSize = AtomicUSize::new(0);
+
+#[inline(always)]
+fn area() -> &'static HartArea {
+    unsafe { &*(crate::target::hart_area() as *const HartArea) }
+}
+
+/// The boot hart takes index 0.  This must come before anything that asks
+/// which hart it is running on (including taking a Spinlock).
+pub fn init(boot_hart_id: usize) {
+    AREAS[0].index.store(0);
+    AREAS[0].hart_id.store(boot_hart_id);
+    COUNT.store_rel(1);
+    crate::target::set_hart_area(&AREAS[0] as *const HartArea as usize);
+}
+
+/// Give the other harts (those with an MMU, in device tree order) their
+/// indices, ready for them to enter().  Needs the device tree.
+pub fn init_from_device_tree() {
+    let cpus = match crate::devicetree::get().and_then(|dt| dt.find("/cpus")) {
+        Some(cpus) => cpus,
+        None => return,
+    };
+    let boot_hart_id = AREAS[0].hart_id.fetch();
+    let mut count = COUNT.fetch();
+    for cpu in cpus.children().filter(|c| c.base_name() == "cpu") {
+        if cpu.property("mmu-type").is_none() {
+            continue; // e.g. the S7 monitor core
+        }
+        let hart_id = match cpu.property_u32("reg") {
+            Some(reg) => reg as usize,
+            None => continue,
+        };
+        if hart_id == boot_hart_id || hart_id >= MAX_HARTS || index_of(hart_id).is_some() {
+            continue;
+        }
+        AREAS[count].index.store(count);
+        AREAS[count].hart_id.store(hart_id);
+        count += 1;
+        COUNT.store_rel(count);
+    }
+}
+
+/// Point this (non-boot) hart's tp at its area.  False if it wasn't given one.
+#[allow(dead_code)]
+pub fn enter(hart_id: usize) -> bool {
+    match index_of(hart_id) {
+        Some(index) => {
+            crate::target::set_hart_area(&AREAS[index] as *const HartArea as usize);
+            true
+        },
+        None => false,
+    }
+}
+
+/// This hart's dense index
+#[inline(always)]
+pub fn this_hart() -> usize {
+    area().index.fetch()
+}
+
+/// This hart's id
+#[inline(always)]
+pub fn hart_id() -> usize {
+    area().hart_id.fetch()
+}
+
+/// How many harts have an index
+#[allow(dead_code)]
+pub fn hart_count() -> usize {
+    COUNT.fetch()
+}
+
+/// The index of the hart with id `hart_id`
+#[allow(dead_code)]
+pub fn index_of(hart_id: usize) -> Option<usize> {
+    AREAS[..COUNT.fetch()].iter().position(|a| a.hart_id.fetch() == hart_id)
+}
+
+/// The id of the hart with index `index`
+#[allow(dead_code)]
+pub fn id_of(index: usize) -> usize {
+    AREAS[index].hart_id.fetch()
+}
+
+/// One T for each hart, by dense index
+pub struct PerHart<T> {
+    values: [T; MAX_HARTS],
+}
+
+impl<T> PerHart<T> {
+    #[allow(dead_code)]
+    pub const fn new(values: [T; MAX_HARTS]) -> PerHart<T> {
+        PerHart { values: values }
+    }
+
+    /// This hart's
+    #[inline(always)]
+    #[allow(dead_code)]
+    pub fn this_hart(&self) -> &T {
+        &self.values[this_hart()]
+    }
+
+    /// The one for the hart with index `index`
+    #[allow(dead_code)]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.values[..hart_count()].get(index)
+    }
+
+    /// Each hart's (index, value)
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.values[..hart_count()].iter().enumerate()
+    }
+}
@@ -3,13 +3,30 @@
 #![no_main]
 #![feature(asm, llvm_asm, global_asm)]
 #![feature(panic_info_message)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
 
 include!("macros.rs");
 
 mod atomic;
+mod cmdline;
 mod device;
+mod devicetree;
+mod dma;
+mod hart;
+#[cfg(lock_bench)]
+mod lock_bench;
+#[cfg(lock_stats)]
+mod lock_stats;
+mod lockdep;
+mod memory;
+mod once;
+mod rcu;
 mod register;
+mod seqlock;
 mod spinlock;
+mod stats;
 mod target;
 
 use target::CONSOLE;
@@ -22,30 +39,85 @@ fn panic(info: &core::panic::PanicInfo) -> !
             kdebug(m.as_bytes());
         }
     }
+    #[cfg(debug_assertions)]
+    memory::debug_heap::display_outstanding();
     crate::target::abort()
 }
 
+#[alloc_error_handler]
+fn alloc_error(_layout: core::alloc::Layout) -> !
+{
+    panic!("Kernel heap exhausted.\n");
+}
+
 
 #[no_mangle]
-extern "C" fn kernel_start() {
+extern "C" fn kernel_start(hart_id: usize, dtb: usize) {
+
+    // Know which hart this is
+    hart::init(hart_id);
 
     // Initialize the hardware
     target::init();
 
+    // Catch traps (and kernel stack overflows)
+    target::trap::init();
+
     // Initialize the CONSOLE
     use device::uart::{Uart, UartParity};
-    unsafe { CONSOLE.set_line_settings(UartParity::None, 8, 1) };
+    CONSOLE.set_line_settings(UartParity::None, 8, 1);
     // For now we leave the baud rate as the default, or whatever target::init()
     // selects.
 
+    // Hand free memory to the frame allocator (and so the kernel heap)
+    memory::init();
+
+    // Find the device tree (through the direct map of the early page table)
+    // and our command line in it
+    devicetree::init(dtb);
+    cmdline::init();
+    spinlock::init();
+    hart::init_from_device_tree();
+    rcu::hart_online();
+    rcu::check();
+
+    // Find the cache maintenance instructions (for DMA)
+    target::cache::init();
+
+    // Replace the early page table from boot.S with the kernel's own
+    target::paging::mode::init();
+    target::paging::pbmt::init();
+    target::paging::kernel::init();
+    target::paging::asid::init();
+
+    // Take cache-resident memory (the L2 LIM) where there is some
+    memory::tim::init();
+
     // Print machine-level information
     target::display_machine_information();
+    memory::map::display();
+    target::paging::display_information();
+
+    stats::display_kernel_stats();
+
+    #[cfg(lock_bench)]
+    lock_bench::run();
 
     // Print a few more things and finish up, as we don't have a useable
     // operating system yet.
     println!("Hello World!\n");
+    println!("Operating System is not yet implemented, idling.");
+
+    idle();
+}
 
-    panic!("Cannot Continue - Operating System is not yet implemented.\n");
+/// What a hart does when it has nothing else to do.  Each time round it
+/// reports a quiescent state, which also runs RCU callbacks that are due.
+fn idle() -> ! {
+    loop {
+        rcu::quiescent_state();
+        target::wait_for_interrupt();
+    }
 }
 
 #[cfg(debug_assertions)]
@@ -53,13 +125,13 @@ extern "C" fn kernel_start() {
 fn kdebug(msg: &[u8]) {
     for c in b"KDEBUG: " {
         unsafe {
-            (crate::target::UART0_ADDR as *mut u8)
+            (crate::memory::mmio_phys_to_virt(crate::target::UART0_ADDR) as *mut u8)
                 .write_volatile(*c);
         }
     }
     for c in msg {
         unsafe {
-            (crate::target::UART0_ADDR as *mut u8)
+            (crate::memory::mmio_phys_to_virt(crate::target::UART0_ADDR) as *mut u8)
                 .write_volatile(*c);
         }
     }
@@ -0,0 +1,129 @@
+// Spinlock contention and hold time statistics.  Built with --cfg lock_stats
+// in CARGO_BUILD_RUSTFLAGS, Spinlock counts, for each class of lock (the type
+// of data it protects, as for lockdep), how often it is taken, how often it
+// had to wait and how long for, and the longest it was held, all in cycles.
+// display() lists the worst.
+//
+// Without lock_stats Spinlock doesn't call in here at all.
+
+use crate::atomic::{Atomic, AtomicBool, AtomicU64, AtomicUSize};
+use crate::target::cycles;
+
+const MAX_CLASSES: usize = 64;
+const DISPLAYED: usize = 10;
+
+struct ClassStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spin_cycles: AtomicU64,
+    max_spin: AtomicU64,
+    max_hold: AtomicU64,
+}
+
+const CLASS_STATS_INIT: ClassStats = ClassStats {
+    acquisitions: AtomicU64::new(0),
+    contended: AtomicU64::new(0),
+    spin_cycles: AtomicU64::new(0),
+    max_spin: AtomicU64::new(0),
+    max_hold: AtomicU64::new(0),
+};
+
+static STATS: [ClassStats; MAX_CLASSES] = [CLASS_STATS_INIT; MAX_CLASSES];
+
+// Names are only added, under ADDING, and are published by CLASSES
+static mut NAMES: [&'static str; MAX_CLASSES] = [""; MAX_CLASSES];
+static CLASSES: AtomicUSize = AtomicUSize::new(0);
+static ADDING: AtomicBool = AtomicBool::new(false);
+
+/// A lock being held, for its hold time
+pub struct Hold {
+    class: Option<usize>,
+    start: u64,
+}
+
+fn find(name: &'static str, classes: usize) -> Option<usize> {
+    unsafe { NAMES[..classes].iter().position(|&n| n == name) }
+}
+
+// The class for `name`, or None if there are too many
+fn class_of(name: &'static str) -> Option<usize> {
+    if let Some(class) = find(name, CLASSES.fetch()) {
+        return Some(class);
+    }
+    while ADDING.compare_and_swap(false, true) {
+        crate::target::pause();
+    }
+    let classes = CLASSES.fetch();
+    let class = find(name, classes).or_else(|| {
+        if classes == MAX_CLASSES {
+            return None;
+        }
+        unsafe { NAMES[classes] = name };
+        CLASSES.store_rel(classes + 1);
+        Some(classes)
+    });
+    ADDING.store_rel(false);
+    class
+}
+
+/// Record taking a lock of class `name` that spun from `spin_start`, and
+/// had to wait if `contended`.  Returns what released() needs.
+pub fn acquired(name: &'static str, spin_start: u64, contended: bool) -> Hold {
+    let now = cycles();
+    let class = class_of(name);
+    if let Some(class) = class {
+        let stats = &STATS[class];
+        stats.acquisitions.fetch_add(1);
+        if contended {
+            let spin = now - spin_start;
+            stats.contended.fetch_add(1);
+            stats.spin_cycles.fetch_add(spin);
+            stats.max_spin.fetch_max(spin);
+        }
+    }
+    Hold { class: class, start: now }
+}
+
+/// Record releasing a lock
+pub fn released(hold: &Hold) {
+    if let Some(class) = hold.class {
+        STATS[class].max_hold.fetch_max(cycles() - hold.start);
+    }
+}
+
+/// List the locks with the most time spent waiting for them
+pub fn display() {
+    let classes = CLASSES.fetch();
+    let mut order = [0; MAX_CLASSES];
+    for i in 0..classes {
+        order[i] = i;
+    }
+    let order = &mut order[..classes];
+    order.sort_unstable_by_key(|&i| core::cmp::Reverse(STATS[i].spin_cycles.fetch()));
+
+    println!("  Locks:      taken  contended   spinning   max spin   max held  (cycles)");
+    for &i in order.iter().take(DISPLAYED) {
+        let stats = &STATS[i];
+        println!("    {:>15} {:>10} {:>10} {:>10} {:>10}  {}",
+                 stats.acquisitions.fetch(), stats.contended.fetch(),
+                 stats.spin_cycles.fetch(), stats.max_spin.fetch(),
+                 stats.max_hold.fetch(), ShortName(unsafe { NAMES[i] }));
+    }
+    if classes == MAX_CLASSES {
+        println!("    (more than {} classes, the rest not counted)", MAX_CLASSES);
+    }
+}
+
+// A class name with only the last path segment of each type in it (e.g.
+// "core::option::Option<winkle::devicetree::DeviceTree>" shows as
+// "Option<DeviceTree>")
+struct ShortName(&'static str);
+
+impl core::fmt::Display for ShortName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for part in self.0.split_inclusive(|c| c == '<' || c == '>' || c == ',' || c == ' ') {
+            f.write_str(part.rsplit("::").next().unwrap_or(part))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+// Lock dependency checking, for debug builds.
+//
+// Every lock belongs to a class, the type of the data it protects (so all the
+// slab caches' locks are one class).  Each hart keeps a list of the locks it
+// holds, and taking a lock records that its class may be taken while holding
+// each of theirs.  Taking class B while holding A, when A was earlier taken
+// while holding B (directly or through other classes), could deadlock, and is
+// reported with the call chains of both orders.  So is a class being taken in
+// an interrupt handler and elsewhere with interrupts enabled, and a hart
+// taking a lock it already holds.
+//
+// Checking stops after the first report.  Release builds compile acquire()
+// and release() to nothing.
+
+#[cfg(debug_assertions)]
+pub use checker::{acquire, release};
+
+/// Note that this hart is about to take `lock`, of class `class`
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn acquire(_class: &'static str, _lock: usize) { }
+
+/// Note that this hart released `lock`
+#[cfg(not(debug_assertions))]
+#[inline(always)]
+pub fn release(_lock: usize) { }
+
+#[cfg(debug_assertions)]
+mod checker {
+    use core::cell::UnsafeCell;
+    use crate::atomic::{Atomic, AtomicBool};
+    use crate::target::{MAX_HARTS, cpu_number};
+    use crate::target::backtrace::backtrace;
+    use crate::target::trap::{in_interrupt, interrupts_enabled};
+
+    const MAX_CLASSES: usize = 32;
+    const MAX_HELD: usize = 16;
+    const CHAIN: usize = 4;
+    // Skip the frames of acquire() and the lock's lock()
+    const SKIP_FRAMES: usize = 2;
+
+    type Chain = [usize; CHAIN];
+
+    struct Graph {
+        names: [&'static str; MAX_CLASSES],
+        count: usize,
+        // Bit b of after[a] is set once class b was taken holding class a,
+        // and chains[a][b] is where that first happened
+        after: [u32; MAX_CLASSES],
+        chains: [[Chain; MAX_CLASSES]; MAX_CLASSES],
+        // Where each class was first taken in an interrupt handler, and
+        // first taken with interrupts enabled
+        in_interrupt: [Option<Chain>; MAX_CLASSES],
+        interrupts_on: [Option<Chain>; MAX_CLASSES],
+    }
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        class: usize,
+        lock: usize,
+        chain: Chain,
+    }
+
+    struct HartState {
+        held: [Held; MAX_HELD],
+        count: usize,
+        busy: bool,     // in acquire(), so ignore the locks println!() takes
+    }
+
+    // Each hart only touches its own
+    struct PerHart(UnsafeCell<HartState>);
+
+    unsafe impl Sync for PerHart {}
+
+    const HELD_INIT: Held = Held { class: 0, lock: 0, chain: [0; CHAIN] };
+    const PER_HART_INIT: PerHart = PerHart(UnsafeCell::new(HartState {
+        held: [HELD_INIT; MAX_HELD],
+        count: 0,
+        busy: false,
+    }));
+
+    static HARTS: [PerHart; MAX_HARTS] = [PER_HART_INIT; MAX_HARTS];
+
+    // The graph can't be behind a Spinlock, as it checks Spinlocks, so it is
+    // only touched with GRAPH_LOCKED set
+    struct SharedGraph(UnsafeCell<Graph>);
+
+    unsafe impl Sync for SharedGraph {}
+
+    static GRAPH_LOCKED: AtomicBool = AtomicBool::new(false);
+    static GRAPH: SharedGraph = SharedGraph(UnsafeCell::new(Graph {
+        names: [""; MAX_CLASSES],
+        count: 0,
+        after: [0; MAX_CLASSES],
+        chains: [[[0; CHAIN]; MAX_CLASSES]; MAX_CLASSES],
+        in_interrupt: [None; MAX_CLASSES],
+        interrupts_on: [None; MAX_CLASSES],
+    }));
+
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+
+    fn print_chain(chain: &Chain) {
+        print!("    at");
+        for &ra in chain.iter().filter(|&&ra| ra != 0) {
+            print!(" {:#x}", ra);
+        }
+        println!();
+    }
+
+    impl Graph {
+        fn class_of(&mut self, name: &'static str) -> Option<usize> {
+            if let Some(class) = self.names[..self.count].iter().position(|&n| n == name) {
+                return Some(class);
+            }
+            if self.count == MAX_CLASSES {
+                return None;
+            }
+            self.names[self.count] = name;
+            self.count += 1;
+            Some(self.count - 1)
+        }
+
+        // Fill `path` with the classes from `from` to `to` along recorded
+        // dependencies, returning its length, or 0 if there is no path
+        fn path(&self, from: usize, to: usize, visited: &mut u32,
+                path: &mut [usize; MAX_CLASSES], depth: usize) -> usize {
+            path[depth] = from;
+            if from == to {
+                return depth + 1;
+            }
+            *visited |= 1 << from;
+            for next in 0..self.count {
+                if self.after[from] & (1 << next) != 0 && *visited & (1 << next) == 0 {
+                    let len = self.path(next, to, visited, path, depth + 1);
+                    if len != 0 {
+                        return len;
+                    }
+                }
+            }
+            0
+        }
+
+        fn report_inversion(&self, held: &Held, class: usize, chain: &Chain,
+                            path: &[usize]) {
+            println!("lockdep: possible deadlock taking {} while holding {}",
+                     self.names[class], self.names[held.class]);
+            println!("  {} was taken", self.names[held.class]);
+            print_chain(&held.chain);
+            println!("  then {}", self.names[class]);
+            print_chain(chain);
+            println!("  but earlier:");
+            for pair in path.windows(2) {
+                println!("  {} was taken holding {}", self.names[pair[1]], self.names[pair[0]]);
+                print_chain(&self.chains[pair[0]][pair[1]]);
+            }
+        }
+
+        fn report_interrupts(&self, class: usize, chain: &Chain, other: &Chain) {
+            println!("lockdep: {} is taken in interrupt handlers and with interrupts enabled",
+                     self.names[class]);
+            println!("  {}", if in_interrupt() { "in an interrupt handler" } else { "with interrupts enabled" });
+            print_chain(chain);
+            println!("  and earlier {}", if in_interrupt() { "with interrupts enabled" } else { "in an interrupt handler" });
+            print_chain(other);
+        }
+
+        // Check taking `lock` with the locks in `hart`, and record it.
+        // Returns false if there was something to report.
+        fn check(&mut self, hart: &mut HartState, name: &'static str, lock: usize,
+                 chain: &Chain) -> bool {
+            let class = match self.class_of(name) {
+                Some(class) => class,
+                None => {
+                    println!("lockdep: more than {} lock classes, no longer checking", MAX_CLASSES);
+                    return false;
+                }
+            };
+            let held = &hart.held[..hart.count];
+
+            if let Some(h) = held.iter().find(|h| h.lock == lock) {
+                println!("lockdep: hart {} taking {} again (a deadlock)", cpu_number(), name);
+                println!("  first");
+                print_chain(&h.chain);
+                println!("  again");
+                print_chain(chain);
+                return false;
+            }
+
+            for h in held.iter().filter(|h| h.class != class) {
+                let mut path = [0; MAX_CLASSES];
+                let len = self.path(class, h.class, &mut 0, &mut path, 0);
+                if len != 0 {
+                    self.report_inversion(h, class, chain, &path[..len]);
+                    return false;
+                }
+            }
+
+            // Taking it with interrupts disabled is fine either way
+            let contexts = if in_interrupt() {
+                Some((&mut self.in_interrupt[class], self.interrupts_on[class]))
+            } else if interrupts_enabled() {
+                Some((&mut self.interrupts_on[class], self.in_interrupt[class]))
+            } else {
+                None
+            };
+            if let Some((this_context, other_context)) = contexts {
+                if this_context.is_none() {
+                    *this_context = Some(*chain);
+                }
+                if let Some(other) = other_context {
+                    self.report_interrupts(class, chain, &other);
+                    return false;
+                }
+            }
+
+            for h in held.iter().filter(|h| h.class != class) {
+                if self.after[h.class] & (1 << class) == 0 {
+                    self.after[h.class] |= 1 << class;
+                    self.chains[h.class][class] = *chain;
+                }
+            }
+
+            if hart.count == MAX_HELD {
+                println!("lockdep: more than {} locks held, no longer checking", MAX_HELD);
+                return false;
+            }
+            hart.held[hart.count] = Held { class: class, lock: lock, chain: *chain };
+            hart.count += 1;
+            true
+        }
+    }
+
+    /// Note that this hart is about to take `lock`, of class `class`
+    pub fn acquire(class: &'static str, lock: usize) {
+        if ! ENABLED.fetch() {
+            return;
+        }
+        let hart = unsafe { &mut *HARTS[cpu_number() as usize].0.get() };
+        if hart.busy {
+            return;
+        }
+        hart.busy = true;
+
+        let mut chain = [0; CHAIN];
+        backtrace(SKIP_FRAMES, &mut chain);
+
+        while GRAPH_LOCKED.compare_and_swap(false, true) {
+            crate::target::pause();
+        }
+        if ENABLED.fetch() {
+            let graph = unsafe { &mut *GRAPH.0.get() };
+            if ! graph.check(hart, class, lock, &chain) {
+                ENABLED.store(false);
+            }
+        }
+        GRAPH_LOCKED.store_rel(false);
+
+        hart.busy = false;
+    }
+
+    /// Note that this hart released `lock`
+    pub fn release(lock: usize) {
+        if ! ENABLED.fetch() {
+            return;
+        }
+        let hart = unsafe { &mut *HARTS[cpu_number() as usize].0.get() };
+        if hart.busy {
+            return;
+        }
+        // Locks needn't be released in the order they were taken
+        if let Some(i) = hart.held[..hart.count].iter().rposition(|h| h.lock == lock) {
+            hart.held.copy_within(i + 1..hart.count, i);
+            hart.count -= 1;
+        }
+    }
+}
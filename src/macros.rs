@@ -14,6 +14,7 @@ macro_rules! println {
 macro_rules! print {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        let _ = unsafe { write!(crate::CONSOLE, $($args)+) };
+        let mut console: &_ = &*crate::CONSOLE;
+        let _ = write!(console, $($args)+);
     });
 }
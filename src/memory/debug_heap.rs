@@ -0,0 +1,204 @@
+// The debug build kernel heap.  Every allocation gets a header and redzones:
+//
+//   | header | front redzone | object ... | back redzone |
+//
+// New objects are filled with POISON_INUSE and freed ones with POISON_FREE,
+// so reads of uninitialized or freed memory stand out.  Freeing checks the
+// redzones (for overruns), the header (for double frees) and, when a block is
+// handed out again, that it wasn't written to while free.  Live allocations
+// are kept on a list, with the call sites they came from, for
+// display_outstanding().
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use crate::spinlock::Lock;
+use crate::target::backtrace::backtrace;
+use super::heap::{raw_alloc, raw_dealloc};
+
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xBB;
+const POISON_INUSE: u8 = 0x5A;
+const POISON_FREE: u8 = 0x6B;
+
+const MAGIC_LIVE: u64 = 0x4C49_5645_4845_4150;  // "LIVEHEAP"
+const MAGIC_FREED: u64 = 0x4652_4545_4845_4150; // "FREEHEAP"
+
+// Return addresses kept per allocation, and how many frames of the allocator
+// itself (this, GlobalAlloc and the alloc crate's shims) to skip
+const SITES: usize = 6;
+const SKIP_FRAMES: usize = 4;
+
+// How many outstanding allocations display_outstanding() lists
+const MAX_DISPLAYED: usize = 32;
+
+#[repr(C)]
+struct Header {
+    link: usize,        // left alone: the slab free list uses a block's first word
+    magic: u64,
+    size: usize,
+    align: usize,
+    prev: *mut Header,
+    next: *mut Header,
+    sites: [usize; SITES],
+}
+
+struct Live {
+    head: *mut Header,
+    count: usize,
+    bytes: usize,
+}
+
+// The list is only followed while the lock is held
+unsafe impl Send for Live {}
+
+static LIVE: Lock<Live> = Lock::new(Live {
+    head: core::ptr::null_mut(),
+    count: 0,
+    bytes: 0,
+});
+
+// The layout actually allocated for `layout`, and the offset of the object
+#[inline(always)]
+fn wrap(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(8);
+    let front = (size_of::<Header>() + REDZONE + align - 1) & !(align - 1);
+    let inner = Layout::from_size_align(front + layout.size() + REDZONE, align).ok()?;
+    Some((inner, front))
+}
+
+#[inline(always)]
+unsafe fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.sub(REDZONE + size_of::<Header>()) as *mut Header
+}
+
+unsafe fn is_filled(ptr: *const u8, len: usize, byte: u8) -> bool {
+    core::slice::from_raw_parts(ptr, len).iter().all(|&b| b == byte)
+}
+
+fn print_sites(sites: &[usize]) {
+    print!("    from");
+    for &site in sites.iter().filter(|&&s| s != 0) {
+        print!(" {:#x}", site);
+    }
+    println!();
+}
+
+// Report heap corruption and stop
+unsafe fn corrupted(what: &str, ptr: *mut u8, header: *const Header) -> ! {
+    println!("Heap: {} at {:p} ({} bytes)", what, ptr, (*header).size);
+    print_sites(&(*header).sites);
+    panic!("Kernel heap corruption.\n");
+}
+
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let (inner, front) = match wrap(layout) {
+        Some(wrapped) => wrapped,
+        None => return core::ptr::null_mut(),
+    };
+    let base = raw_alloc(inner);
+    if base.is_null() {
+        return base;
+    }
+    let ptr = base.add(front);
+    let header = header_of(ptr);
+
+    // If this block was freed by us (from where it is now), it should still
+    // be all poison
+    if (*header).magic == MAGIC_FREED {
+        let len = (*header).size.min(layout.size());
+        if ! is_filled(ptr, len, POISON_FREE) {
+            corrupted("write after free", ptr, header);
+        }
+    }
+
+    core::ptr::write_bytes(ptr.sub(REDZONE), REDZONE_BYTE, REDZONE);
+    core::ptr::write_bytes(ptr, POISON_INUSE, layout.size());
+    core::ptr::write_bytes(ptr.add(layout.size()), REDZONE_BYTE, REDZONE);
+
+    (*header).magic = MAGIC_LIVE;
+    (*header).size = layout.size();
+    (*header).align = layout.align();
+    (*header).prev = core::ptr::null_mut();
+    (*header).sites = [0; SITES];
+    backtrace(SKIP_FRAMES, &mut (*header).sites);
+
+    let mut live = LIVE.lock();
+    (*header).next = live.head;
+    if ! live.head.is_null() {
+        (*live.head).prev = header;
+    }
+    live.head = header;
+    live.count += 1;
+    live.bytes += layout.size();
+
+    ptr
+}
+
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    let header = header_of(ptr);
+    match (*header).magic {
+        MAGIC_LIVE => { },
+        MAGIC_FREED => corrupted("double free", ptr, header),
+        _ => corrupted("free of a bad pointer (or header overwritten)", ptr, header),
+    }
+    if (*header).size != layout.size() || (*header).align != layout.align() {
+        corrupted("freed with a different layout", ptr, header);
+    }
+    if ! is_filled(ptr.sub(REDZONE), REDZONE, REDZONE_BYTE) {
+        corrupted("buffer underrun", ptr, header);
+    }
+    if ! is_filled(ptr.add(layout.size()), REDZONE, REDZONE_BYTE) {
+        corrupted("buffer overrun", ptr, header);
+    }
+
+    {
+        let mut live = LIVE.lock();
+        if (*header).prev.is_null() {
+            live.head = (*header).next;
+        } else {
+            (*(*header).prev).next = (*header).next;
+        }
+        if ! (*header).next.is_null() {
+            (*(*header).next).prev = (*header).prev;
+        }
+        live.count -= 1;
+        live.bytes -= layout.size();
+    }
+
+    (*header).magic = MAGIC_FREED;
+    core::ptr::write_bytes(ptr, POISON_FREE, layout.size());
+
+    let (inner, front) = wrap(layout).unwrap();
+    raw_dealloc(ptr.sub(front), inner);
+}
+
+/// The number of live allocations and the bytes in them
+pub fn outstanding() -> (usize, usize) {
+    let live = LIVE.lock();
+    (live.count, live.bytes)
+}
+
+/// List live allocations (most recent first) and where they were made.  Safe
+/// to call while panicking, unless the heap was in use.
+pub fn display_outstanding() {
+    if unsafe { LIVE.is_locked() } {
+        println!("Heap: in use, can't list allocations");
+        return;
+    }
+    let live = LIVE.lock();
+    println!("Heap: {} allocations outstanding ({} bytes)", live.count, live.bytes);
+    let mut header = live.head;
+    let mut shown = 0;
+    while ! header.is_null() && shown < MAX_DISPLAYED {
+        unsafe {
+            let ptr = (header as *mut u8).add(size_of::<Header>() + REDZONE);
+            println!("  {} bytes at {:p}", (*header).size, ptr);
+            print_sites(&(*header).sites);
+            header = (*header).next;
+        }
+        shown += 1;
+    }
+    if shown < live.count {
+        println!("  ... and {} more", live.count - shown);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::spinlock::Lock;
+use super::{PAGE_SIZE, PAGE_SHIFT, phys_to_virt, page_round_up, page_round_down};
+
+/// Physical page frame allocator.
+///
+/// Keeps one bit per 4K frame (set = in use), and a reference count per frame
+/// so frames can be shared (e.g. copy-on-write) and are only freed when the
+/// last reference is dropped.  The bitmap and counts live in the first frames
+/// of the managed region, which are marked in use.
+pub struct FrameAllocator {
+    base: usize,       // physical address of frame 0
+    frames: usize,     // number of frames managed
+    free: usize,       // number of free frames
+    next: usize,       // where the next search starts
+    bitmap: *mut u64,
+    refs: *mut u32,
+}
+
+static FRAMES: Lock<FrameAllocator> = Lock::new(FrameAllocator::empty());
+
+impl FrameAllocator {
+    const fn empty() -> FrameAllocator {
+        FrameAllocator {
+            base: 0,
+            frames: 0,
+            free: 0,
+            next: 0,
+            bitmap: core::ptr::null_mut(),
+            refs: core::ptr::null_mut(),
+        }
+    }
+
+    #[inline(always)]
+    fn is_used(&self, frame: usize) -> bool {
+        unsafe { *self.bitmap.add(frame / 64) & (1 << (frame % 64)) != 0 }
+    }
+
+    #[inline(always)]
+    fn set_used(&mut self, frame: usize) {
+        unsafe { *self.bitmap.add(frame / 64) |= 1 << (frame % 64) };
+        unsafe { *self.refs.add(frame) = 1 };
+        self.free -= 1;
+    }
+
+    #[inline(always)]
+    fn set_free(&mut self, frame: usize) {
+        unsafe { *self.bitmap.add(frame / 64) &= !(1 << (frame % 64)) };
+        self.free += 1;
+    }
+
+    // Find `count` free frames in a row, the first of which is a multiple of
+    // `align` frames.  Returns the first frame number.
+    fn find_run(&self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.free { return None; }
+
+        // Alignment is of the physical frame number, not the index
+        let base_pfn = self.base >> PAGE_SHIFT;
+        let align_up = |f: usize| {
+            (base_pfn + f + align - 1) / align * align - base_pfn
+        };
+
+        // Search from the hint first, then from the start
+        for &from in &[self.next, 0] {
+            let mut frame = align_up(from);
+            while frame + count <= self.frames {
+                match (frame..frame + count).find(|&f| self.is_used(f)) {
+                    None => return Some(frame),
+                    Some(used) => frame = align_up(used + 1),
+                }
+            }
+        }
+        None
+    }
+
+    fn alloc(&mut self, count: usize, align: usize) -> Option<usize> {
+        let first = self.find_run(count, align.max(1))?;
+        for frame in first..first + count {
+            self.set_used(frame);
+        }
+        self.next = first + count;
+        if self.next >= self.frames { self.next = 0; }
+        Some(self.base + (first << PAGE_SHIFT))
+    }
+
+    // The frame number of `pa`, which must be managed here
+    #[inline(always)]
+    fn frame_of(&self, pa: usize) -> usize {
+        if pa < self.base || pa >= self.base + (self.frames << PAGE_SHIFT) {
+            panic!("Not a managed frame.\n");
+        }
+        (pa - self.base) >> PAGE_SHIFT
+    }
+
+    // Drop a reference to each of `count` frames, freeing those left with none
+    fn dealloc(&mut self, pa: usize, count: usize) {
+        let first = self.frame_of(pa);
+        for frame in first..first + count {
+            if cfg!(debug_assertions) {
+                if ! self.is_used(frame) {
+                    panic!("frame freed, but not allocated!!!");
+                }
+            }
+            let refs = unsafe { &mut *self.refs.add(frame) };
+            *refs -= 1;
+            if *refs == 0 {
+                self.set_free(frame);
+            }
+        }
+    }
+
+    fn get(&mut self, pa: usize) {
+        let frame = self.frame_of(pa);
+        if cfg!(debug_assertions) {
+            if ! self.is_used(frame) {
+                panic!("frame shared, but not allocated!!!");
+            }
+        }
+        unsafe { *self.refs.add(frame) += 1 };
+    }
+
+    fn refs(&self, pa: usize) -> u32 {
+        unsafe { *self.refs.add(self.frame_of(pa)) }
+    }
+}
+
+// The bitmap pointer is only dereferenced while the lock is held
+unsafe impl Send for FrameAllocator {}
+
+/// Take over the physical memory from `start` to `end`
+pub fn init(start: usize, end: usize) {
+    let start = page_round_up(start);
+    let end = page_round_down(end);
+    let frames = (end - start) / PAGE_SIZE;
+
+    let mut fa = FRAMES.lock();
+    fa.base = start;
+    fa.frames = frames;
+    fa.free = frames;
+    fa.next = 0;
+    fa.bitmap = phys_to_virt(start) as *mut u64;
+
+    // The reference counts follow the bitmap
+    let words = (frames + 63) / 64;
+    fa.refs = unsafe { fa.bitmap.add(words) as *mut u32 };
+
+    // Clear the bitmap, then mark the frames holding it and the counts as
+    // used.  Counts of free frames are never read, so need no clearing.
+    for i in 0..words {
+        unsafe { fa.bitmap.add(i).write(0) };
+    }
+    let bitmap_frames = page_round_up(words * 8 + frames * 4) / PAGE_SIZE;
+    for frame in 0..bitmap_frames {
+        fa.set_used(frame);
+    }
+    fa.next = bitmap_frames;
+}
+
+/// Allocate a single frame, returning its physical address
+#[allow(dead_code)]
+pub fn alloc_frame() -> Option<usize> {
+    FRAMES.lock().alloc(1, 1)
+}
+
+/// Allocate a single frame and fill it with zeroes
+#[allow(dead_code)]
+pub fn alloc_frame_zeroed() -> Option<usize> {
+    let pa = alloc_frame()?;
+    unsafe { core::ptr::write_bytes(phys_to_virt(pa) as *mut u8, 0, PAGE_SIZE) };
+    Some(pa)
+}
+
+/// Allocate `count` physically contiguous frames, the first of which has a
+/// physical frame number that is a multiple of `align`.
+#[allow(dead_code)]
+pub fn alloc_frames(count: usize, align: usize) -> Option<usize> {
+    FRAMES.lock().alloc(count, align)
+}
+
+/// Drop a reference to a frame, freeing it if that was the last
+#[allow(dead_code)]
+pub fn free_frame(pa: usize) {
+    FRAMES.lock().dealloc(pa, 1)
+}
+
+#[allow(dead_code)]
+pub fn free_frames(pa: usize, count: usize) {
+    FRAMES.lock().dealloc(pa, count)
+}
+
+/// Take another reference to an allocated frame (to share it), which is then
+/// only freed once `free_frame()` has been called once more
+#[allow(dead_code)]
+pub fn get_frame(pa: usize) {
+    FRAMES.lock().get(pa)
+}
+
+/// The number of references to an allocated frame
+#[allow(dead_code)]
+pub fn frame_refs(pa: usize) -> u32 {
+    FRAMES.lock().refs(pa)
+}
+
+pub fn free_count() -> usize {
+    FRAMES.lock().free
+}
+
+pub fn total_count() -> usize {
+    FRAMES.lock().frames
+}
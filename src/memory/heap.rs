@@ -0,0 +1,188 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use crate::atomic::{Atomic, AtomicUSize};
+use crate::hart::{self, PerHart};
+use crate::target::MAX_HARTS;
+use super::{frame, slab, PAGE_SIZE, phys_to_virt, virt_to_phys, page_round_up};
+
+// Each hart keeps a magazine of free objects per size class in front of the
+// slab caches, so most allocations and frees never touch a shared lock.
+// Magazines refill from and flush to the slabs MAGAZINE_BATCH at a time.
+const MAGAZINE_SIZE: usize = 32;
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+
+struct Magazine {
+    count: usize,
+    objs: [*mut u8; MAGAZINE_SIZE],
+}
+
+const MAGAZINE_INIT: Magazine = Magazine {
+    count: 0,
+    objs: [core::ptr::null_mut(); MAGAZINE_SIZE],
+};
+
+/// Allocation counters, kept per hart
+pub struct HeapStats {
+    pub allocs: AtomicUSize,
+    pub frees: AtomicUSize,
+    pub remote_frees: AtomicUSize,
+    pub refills: AtomicUSize,
+    pub flushes: AtomicUSize,
+    pub large_allocs: AtomicUSize,
+}
+
+impl HeapStats {
+    const fn new() -> HeapStats {
+        HeapStats {
+            allocs: AtomicUSize::new(0),
+            frees: AtomicUSize::new(0),
+            remote_frees: AtomicUSize::new(0),
+            refills: AtomicUSize::new(0),
+            flushes: AtomicUSize::new(0),
+            large_allocs: AtomicUSize::new(0),
+        }
+    }
+}
+
+// Only the owning hart writes, so a plain (non-AMO) increment is enough
+#[inline(always)]
+fn bump(counter: &AtomicUSize) {
+    counter.store(counter.fetch() + 1);
+}
+
+struct HartHeap {
+    magazines: UnsafeCell<[Magazine; slab::NUM_CLASSES]>,
+    stats: HeapStats,
+}
+
+// The magazines are only ever touched by the hart they belong to, and never
+// from interrupt context.
+unsafe impl Sync for HartHeap {}
+
+const HART_HEAP_INIT: HartHeap = HartHeap {
+    magazines: UnsafeCell::new([MAGAZINE_INIT; slab::NUM_CLASSES]),
+    stats: HeapStats::new(),
+};
+
+static HART_HEAPS: PerHart<HartHeap> = PerHart::new([HART_HEAP_INIT; MAX_HARTS]);
+
+pub struct Heap;
+
+#[global_allocator]
+static HEAP: Heap = Heap;
+
+// Debug builds wrap every allocation with redzones and tracking (see
+// debug_heap.rs)
+unsafe impl GlobalAlloc for Heap {
+    #[cfg(not(debug_assertions))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        raw_alloc(layout)
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        raw_dealloc(ptr, layout)
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::debug_heap::alloc(layout)
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        super::debug_heap::dealloc(ptr, layout)
+    }
+}
+
+pub(super) unsafe fn raw_alloc(layout: Layout) -> *mut u8 {
+    let hart = hart::this_hart();
+    let hh = HART_HEAPS.this_hart();
+
+    let class = match slab::size_class(layout.size(), layout.align()) {
+        Some(class) => class,
+        None => {
+            bump(&hh.stats.large_allocs);
+            return alloc_large(layout);
+        }
+    };
+
+    let mag = &mut (*hh.magazines.get())[class];
+    if mag.count == 0 {
+        mag.count = slab::refill(class, hart, &mut mag.objs[..MAGAZINE_BATCH]);
+        bump(&hh.stats.refills);
+        if mag.count == 0 {
+            return core::ptr::null_mut();
+        }
+    }
+    mag.count -= 1;
+    bump(&hh.stats.allocs);
+    mag.objs[mag.count]
+}
+
+pub(super) unsafe fn raw_dealloc(ptr: *mut u8, layout: Layout) {
+    let hart = hart::this_hart();
+    let hh = HART_HEAPS.this_hart();
+
+    let class = match slab::size_class(layout.size(), layout.align()) {
+        Some(class) => class,
+        None => return dealloc_large(ptr, layout),
+    };
+
+    bump(&hh.stats.frees);
+
+    // Objects from another hart's slab go straight back to that slab
+    if slab::owner(ptr) != hart {
+        bump(&hh.stats.remote_frees);
+        slab::release(class, &[ptr]);
+        return;
+    }
+
+    let mag = &mut (*hh.magazines.get())[class];
+    if mag.count == MAGAZINE_SIZE {
+        // Flush the oldest batch, keep the most recently freed (and most
+        // likely cache-hot) objects
+        slab::release(class, &mag.objs[..MAGAZINE_BATCH]);
+        mag.objs.copy_within(MAGAZINE_BATCH.., 0);
+        mag.count -= MAGAZINE_BATCH;
+        bump(&hh.stats.flushes);
+    }
+    mag.objs[mag.count] = ptr;
+    mag.count += 1;
+}
+
+unsafe fn alloc_large(layout: Layout) -> *mut u8 {
+    let frames = page_round_up(layout.size()) / PAGE_SIZE;
+    let align = (layout.align() / PAGE_SIZE).max(1);
+    match frame::alloc_frames(frames, align) {
+        Some(pa) => phys_to_virt(pa) as *mut u8,
+        None => core::ptr::null_mut(),
+    }
+}
+
+unsafe fn dealloc_large(ptr: *mut u8, layout: Layout) {
+    let frames = page_round_up(layout.size()) / PAGE_SIZE;
+    frame::free_frames(virt_to_phys(ptr as usize), frames);
+}
+
+pub fn display_stats() {
+    #[cfg(debug_assertions)]
+    {
+        let (count, bytes) = super::debug_heap::outstanding();
+        println!("  Debug heap: {} allocations outstanding ({} bytes)", count, bytes);
+    }
+    println!("  Slabs:");
+    for (class, size) in slab::SIZE_CLASSES.iter().enumerate() {
+        println!("    {:>4} bytes: {} slabs", size, slab::slab_count(class));
+    }
+    println!("  Heap (per hart):");
+    for (hart, hh) in HART_HEAPS.iter() {
+        let s = &hh.stats;
+        if s.allocs.fetch() == 0 && s.large_allocs.fetch() == 0 {
+            continue;
+        }
+        println!("    hart {}: allocs={} frees={} remote_frees={} refills={} flushes={} large={}",
+                 hart::id_of(hart), s.allocs.fetch(), s.frees.fetch(), s.remote_frees.fetch(),
+                 s.refills.fetch(), s.flushes.fetch(), s.large_allocs.fetch());
+    }
+}
@@ -0,0 +1,51 @@
+// Addresses provided by the linker script (link.lds).  Those within the
+// kernel image are virtual, _memory_start and _memory_end are physical, and
+// _stack_size and _stack_guard_size are sizes.
+
+extern "C" {
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+    static _data_end: u8;
+    static _bss_start: u8;
+    static _bss_end: u8;
+    static _stacks_start: u8;
+    static _stacks_end: u8;
+    static _stack_size: u8;
+    static _stack_guard_size: u8;
+    static _heap_start: u8;
+    static _heap_end: u8;
+    static _memory_start: u8;
+    static _memory_end: u8;
+    static _kernel_offset: u8;
+}
+
+macro_rules! linker_symbol {
+    ($name:ident, $symbol:ident) => (
+        #[allow(dead_code)]
+        #[inline(always)]
+        pub fn $name() -> usize {
+            unsafe { &$symbol as *const u8 as usize }
+        }
+    );
+}
+
+linker_symbol!(text_start, _text_start);
+linker_symbol!(text_end, _text_end);
+linker_symbol!(rodata_start, _rodata_start);
+linker_symbol!(rodata_end, _rodata_end);
+linker_symbol!(data_start, _data_start);
+linker_symbol!(data_end, _data_end);
+linker_symbol!(bss_start, _bss_start);
+linker_symbol!(bss_end, _bss_end);
+linker_symbol!(stacks_start, _stacks_start);
+linker_symbol!(stacks_end, _stacks_end);
+linker_symbol!(stack_size, _stack_size);
+linker_symbol!(stack_guard_size, _stack_guard_size);
+linker_symbol!(heap_start, _heap_start);
+linker_symbol!(heap_end, _heap_end);
+linker_symbol!(memory_start, _memory_start);
+linker_symbol!(memory_end, _memory_end);
+linker_symbol!(kernel_offset, _kernel_offset);
@@ -0,0 +1,149 @@
+// The physical memory map, put together from the linker symbols (where the
+// kernel image is) and the device tree (where memory is, and what in it
+// belongs to firmware, the initrd and the device tree itself), for showing
+// at boot.  Overlapping regions are flagged, as they are almost always a
+// mistake in a link.lds or a device tree.
+
+use alloc::vec::Vec;
+use crate::devicetree;
+use super::{layout, kernel_virt_to_phys, virt_to_phys};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum RegionKind {
+    /// Memory, as the device tree describes it (the other kinds lie within)
+    Ram,
+    /// Part of the kernel image
+    Kernel,
+    /// Reserved for firmware (or anything else the kernel must not touch)
+    Firmware,
+    Initrd,
+    Dtb,
+    /// Given to the frame allocator
+    Free,
+}
+
+impl RegionKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RegionKind::Ram => "ram",
+            RegionKind::Kernel => "kernel",
+            RegionKind::Firmware => "firmware",
+            RegionKind::Initrd => "initrd",
+            RegionKind::Dtb => "dtb",
+            RegionKind::Free => "free",
+        }
+    }
+}
+
+/// A region of physical memory, from `start` up to (not including) `end`
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+    pub name: &'static str,
+}
+
+impl Region {
+    #[inline(always)]
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+fn push(map: &mut Vec<Region>, start: usize, end: usize, kind: RegionKind, name: &'static str) {
+    if end > start {
+        map.push(Region { start: start, end: end, kind: kind, name: name });
+    }
+}
+
+// A part of the kernel image, from its linked addresses
+fn push_image(map: &mut Vec<Region>, start: usize, end: usize, name: &'static str) {
+    push(map, kernel_virt_to_phys(start), kernel_virt_to_phys(end), RegionKind::Kernel, name);
+}
+
+/// Put together the memory map, sorted by start address
+pub fn build() -> Vec<Region> {
+    let mut map = Vec::new();
+
+    push_image(&mut map, layout::text_start(), layout::text_end(), ".text");
+    push_image(&mut map, layout::rodata_start(), layout::rodata_end(), ".rodata");
+    push_image(&mut map, layout::data_start(), layout::data_end(), ".data");
+    push_image(&mut map, layout::bss_start(), layout::bss_end(), ".bss");
+    push_image(&mut map, layout::stacks_start(), layout::stacks_end(), ".stack");
+    push(&mut map, kernel_virt_to_phys(layout::heap_start()),
+         kernel_virt_to_phys(layout::heap_end()), RegionKind::Free, "frames");
+
+    if let Some(dt) = devicetree::get() {
+        let root = dt.root();
+        let (ac, sc) = (root.address_cells(), root.size_cells());
+
+        for node in root.children().filter(|n| n.property_str("device_type") == Some("memory")) {
+            for (address, size) in node.reg(ac, sc) {
+                push(&mut map, address as usize, (address + size) as usize,
+                     RegionKind::Ram, node.name());
+            }
+        }
+
+        if let Some(reserved) = root.child("reserved-memory") {
+            let (rac, rsc) = (reserved.address_cells(), reserved.size_cells());
+            for node in reserved.children() {
+                for (address, size) in node.reg(rac, rsc) {
+                    push(&mut map, address as usize, (address + size) as usize,
+                         RegionKind::Firmware, node.name());
+                }
+            }
+        }
+
+        let mut initrd = None;
+        if let Some(chosen) = root.child("chosen") {
+            if let (Some(start), Some(end)) = (chosen.property_cells("linux,initrd-start"),
+                                               chosen.property_cells("linux,initrd-end")) {
+                initrd = Some((start as usize, end as usize));
+                push(&mut map, start as usize, end as usize, RegionKind::Initrd, "initrd");
+            }
+        }
+
+        let dtb = virt_to_phys(dt.address());
+        push(&mut map, dtb, dtb + dt.size(), RegionKind::Dtb, "dtb");
+
+        // The reservation block commonly repeats the initrd and the DTB
+        for (address, size) in dt.reservations() {
+            let (start, end) = (address as usize, (address + size) as usize);
+            if Some((start, end)) == initrd || (start == dtb && end >= dtb + dt.size()) {
+                continue;
+            }
+            push(&mut map, start, end, RegionKind::Firmware, "memreserve");
+        }
+    }
+
+    map.sort_unstable_by_key(|r| (r.start, r.kind));
+    map
+}
+
+/// Print the memory map, flagging overlaps between regions (other than with
+/// the RAM they are in) and regions outside of RAM
+pub fn display() {
+    let map = build();
+    let has_ram = map.iter().any(|r| r.kind == RegionKind::Ram);
+
+    println!("Physical memory map:");
+    for (i, region) in map.iter().enumerate() {
+        println!("  {:#018x} - {:#018x}  {:<8}  {}",
+                 region.start, region.end - 1, region.kind.name(), region.name);
+        if region.kind == RegionKind::Ram {
+            continue;
+        }
+        for (j, other) in map.iter().enumerate() {
+            if i != j && other.kind != RegionKind::Ram && region.overlaps(other) {
+                println!("      ** overlaps {} {}", other.kind.name(), other.name);
+            }
+        }
+        let in_ram = map.iter().any(|r| {
+            r.kind == RegionKind::Ram && r.start <= region.start && region.end <= r.end
+        });
+        if has_ram && ! in_ram && region.kind != RegionKind::Dtb {
+            println!("      ** not within RAM");
+        }
+    }
+}
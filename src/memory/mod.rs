@@ -0,0 +1,82 @@
+#[cfg(debug_assertions)]
+pub mod debug_heap;
+pub mod frame;
+pub mod heap;
+pub mod layout;
+pub mod map;
+pub mod slab;
+pub mod stack;
+pub mod tim;
+pub mod vm;
+
+pub const PAGE_SIZE: usize = 4096;
+#[allow(dead_code)]
+pub const PAGE_SHIFT: usize = 12;
+
+/// Where all of physical memory is mapped (the direct map), up to the MMIO map
+pub const DIRECT_MAP_BASE: usize = 0xFFFF_FFC0_0000_0000;
+
+/// Where device registers are mapped (the MMIO map), with the IO memory type.
+/// Like the direct map it is in the kernel half, so every address space
+/// shares it.
+pub const MMIO_MAP_BASE: usize = 0xFFFF_FFE0_0000_0000;
+
+/// Where the kernel image is linked (see link.lds)
+#[allow(dead_code)]
+pub const KERNEL_VIRT_BASE: usize = 0xFFFF_FFFF_8000_0000;
+
+/// Convert a physical address into the virtual address the kernel uses to
+/// reach it (in the direct map)
+#[allow(dead_code)]
+#[inline(always)]
+pub fn phys_to_virt(pa: usize) -> usize {
+    pa + DIRECT_MAP_BASE
+}
+
+/// Convert the physical address of device registers into the virtual address
+/// the kernel uses to reach them (in the MMIO map)
+#[allow(dead_code)]
+#[inline(always)]
+pub const fn mmio_phys_to_virt(pa: usize) -> usize {
+    pa + MMIO_MAP_BASE
+}
+
+/// Convert a direct map address (as returned by `phys_to_virt`) back into a
+/// physical address
+#[allow(dead_code)]
+#[inline(always)]
+pub fn virt_to_phys(va: usize) -> usize {
+    va - DIRECT_MAP_BASE
+}
+
+/// Convert an address within the kernel image into a physical address
+#[inline(always)]
+pub fn kernel_virt_to_phys(va: usize) -> usize {
+    va - layout::kernel_offset()
+}
+
+#[inline(always)]
+pub const fn page_round_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[inline(always)]
+pub const fn page_round_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+/// Hand the memory after the kernel image over to the frame allocator.  The
+/// kernel heap is usable after this returns.
+pub fn init() {
+    frame::init(kernel_virt_to_phys(layout::heap_start()),
+                kernel_virt_to_phys(layout::heap_end()));
+}
+
+pub fn display_stats() {
+    println!("Memory:");
+    println!("  Frames: {} free of {}", frame::free_count(), frame::total_count());
+    if tim::total_count() > 0 {
+        println!("  TIM pages: {} free of {}", tim::free_count(), tim::total_count());
+    }
+    heap::display_stats();
+}
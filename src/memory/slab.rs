@@ -0,0 +1,194 @@
+use core::mem::size_of;
+use crate::spinlock::Lock;
+use crate::target::MAX_HARTS;
+use super::{frame, PAGE_SIZE, phys_to_virt, virt_to_phys, page_round_down};
+
+/// Object sizes served by the slab caches.  Anything bigger (or more strictly
+/// aligned) is given whole frames.
+pub const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+pub const NUM_CLASSES: usize = SIZE_CLASSES.len();
+
+/// Get the index of the smallest size class that fits `size` bytes at
+/// `align` alignment, if any.
+#[inline(always)]
+pub fn size_class(size: usize, align: usize) -> Option<usize> {
+    let need = size.max(align);
+    SIZE_CLASSES.iter().position(|&s| s >= need)
+}
+
+// A slab is one frame, starting with this header and followed by objects.
+// Every slab belongs to the hart that created it; only that hart caches its
+// objects in a magazine, all other harts give them straight back.
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,         // next slab in the owner's partial list
+    free: *mut FreeObject,   // free objects in this slab
+    inuse: usize,            // objects handed out (including in magazines)
+    class: usize,
+    owner: usize,
+    partial: bool,           // whether this slab is on a partial list
+}
+
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+struct SlabCache {
+    // Slabs with at least one free object, by owning hart (its index, see
+    // hart.rs)
+    partial: [*mut Slab; MAX_HARTS],
+    slabs: usize,
+}
+
+// Slab pointers are only dereferenced while the cache lock is held
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new() -> SlabCache {
+        SlabCache {
+            partial: [core::ptr::null_mut(); MAX_HARTS],
+            slabs: 0,
+        }
+    }
+
+    // Offset of the first object, keeping every object aligned to its size
+    fn first_object(class: usize) -> usize {
+        let size = SIZE_CLASSES[class];
+        (size_of::<Slab>() + size - 1) / size * size
+    }
+
+    fn new_slab(&mut self, class: usize, owner: usize) -> Option<*mut Slab> {
+        let va = phys_to_virt(frame::alloc_frame()?);
+        let size = SIZE_CLASSES[class];
+
+        // Thread every object onto the free list
+        let mut free: *mut FreeObject = core::ptr::null_mut();
+        let mut offset = PAGE_SIZE - size;
+        while offset >= Self::first_object(class) {
+            let obj = (va + offset) as *mut FreeObject;
+            unsafe { (*obj).next = free };
+            free = obj;
+            offset -= size;
+        }
+
+        let slab = va as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                next: self.partial[owner],
+                free: free,
+                inuse: 0,
+                class: class,
+                owner: owner,
+                partial: true,
+            });
+        }
+        self.partial[owner] = slab;
+        self.slabs += 1;
+        Some(slab)
+    }
+
+    fn unlink_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            let mut link: *mut *mut Slab = &mut self.partial[(*slab).owner];
+            while ! (*link).is_null() {
+                if *link == slab {
+                    *link = (*slab).next;
+                    break;
+                }
+                link = &mut (**link).next;
+            }
+            (*slab).partial = false;
+        }
+    }
+
+    fn refill(&mut self, class: usize, owner: usize, out: &mut [*mut u8]) -> usize {
+        let mut n = 0;
+        while n < out.len() {
+            let slab = if self.partial[owner].is_null() {
+                match self.new_slab(class, owner) {
+                    Some(slab) => slab,
+                    None => break,
+                }
+            } else {
+                self.partial[owner]
+            };
+
+            unsafe {
+                while n < out.len() && ! (*slab).free.is_null() {
+                    let obj = (*slab).free;
+                    (*slab).free = (*obj).next;
+                    (*slab).inuse += 1;
+                    out[n] = obj as *mut u8;
+                    n += 1;
+                }
+                if (*slab).free.is_null() {
+                    self.unlink_partial(slab);
+                }
+            }
+        }
+        n
+    }
+
+    fn release(&mut self, obj: *mut u8) {
+        let slab = slab_of(obj);
+        unsafe {
+            let obj = obj as *mut FreeObject;
+            (*obj).next = (*slab).free;
+            (*slab).free = obj;
+            (*slab).inuse -= 1;
+
+            if (*slab).inuse == 0 {
+                // Give the whole frame back
+                if (*slab).partial {
+                    self.unlink_partial(slab);
+                }
+                self.slabs -= 1;
+                frame::free_frame(virt_to_phys(slab as usize));
+            } else if ! (*slab).partial {
+                let owner = (*slab).owner;
+                (*slab).next = self.partial[owner];
+                (*slab).partial = true;
+                self.partial[owner] = slab;
+            }
+        }
+    }
+}
+
+const SLAB_CACHE_INIT: Lock<SlabCache> = Lock::new(SlabCache::new());
+static CACHES: [Lock<SlabCache>; NUM_CLASSES] = [SLAB_CACHE_INIT; NUM_CLASSES];
+
+#[inline(always)]
+fn slab_of(obj: *mut u8) -> *mut Slab {
+    page_round_down(obj as usize) as *mut Slab
+}
+
+/// The hart that owns the slab `obj` was allocated from
+#[inline(always)]
+pub fn owner(obj: *mut u8) -> usize {
+    // The owner never changes while objects are outstanding
+    unsafe { (*slab_of(obj)).owner }
+}
+
+/// Take up to `out.len()` objects of size class `class` from slabs owned by
+/// `owner`, making new slabs as needed.  Returns how many were taken.
+pub fn refill(class: usize, owner: usize, out: &mut [*mut u8]) -> usize {
+    CACHES[class].lock().refill(class, owner, out)
+}
+
+/// Give objects of size class `class` back to the slabs they came from
+pub fn release(class: usize, objs: &[*mut u8]) {
+    let mut cache = CACHES[class].lock();
+    for &obj in objs {
+        if cfg!(debug_assertions) {
+            if unsafe { (*slab_of(obj)).class } != class {
+                panic!("object released into the wrong slab cache!!!");
+            }
+        }
+        cache.release(obj);
+    }
+}
+
+/// Number of slabs currently held by size class `class`
+pub fn slab_count(class: usize) -> usize {
+    CACHES[class].lock().slabs
+}
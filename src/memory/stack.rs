@@ -0,0 +1,131 @@
+// Kernel stacks, each with an unmapped guard page below it, so that running
+// off the bottom of a stack faults (see target::trap) instead of silently
+// corrupting whatever lies below.
+//
+// The boot stacks, one per hart, are laid out in link.lds.  Kernel thread
+// stacks are mapped on demand in their own region of the address space.
+
+use alloc::vec::Vec;
+use crate::spinlock::Lock;
+use crate::target::paging::PteFlags;
+use crate::target::paging::kernel::KERNEL_PAGE_TABLE;
+use super::{frame, layout, PAGE_SIZE};
+
+/// Where kernel thread stacks are mapped: the 2 GiB below the kernel image
+pub const THREAD_STACKS_BASE: usize = 0xFFFF_FFFF_0000_0000;
+pub const THREAD_STACKS_SIZE: usize = 0x8000_0000;
+
+/// The size of a kernel thread stack
+pub const THREAD_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The size of the unmapped gap below each kernel thread stack
+pub const THREAD_STACK_GUARD_SIZE: usize = PAGE_SIZE;
+
+const THREAD_STACK_STRIDE: usize = THREAD_STACK_GUARD_SIZE + THREAD_STACK_SIZE;
+const THREAD_STACK_SLOTS: usize = THREAD_STACKS_SIZE / THREAD_STACK_STRIDE;
+
+#[inline(always)]
+fn boot_stack_stride() -> usize {
+    layout::stack_guard_size() + layout::stack_size()
+}
+
+/// The number of boot stacks in link.lds (which may be more than MAX_HARTS)
+pub fn boot_stack_count() -> usize {
+    (layout::stacks_end() - layout::stacks_start()) / boot_stack_stride()
+}
+
+/// The boot stack of `hart`, as (lowest usable address, top)
+pub fn boot_stack(hart: usize) -> (usize, usize) {
+    let top = layout::stacks_end() - hart * boot_stack_stride();
+    (top - layout::stack_size(), top)
+}
+
+/// Whether `va` is in the guard page of any kernel stack
+pub fn is_guard_page(va: usize) -> bool {
+    if va >= layout::stacks_start() && va < layout::stacks_end() {
+        // Guards are at the bottom of each (guard, stack) slot
+        return (va - layout::stacks_start()) % boot_stack_stride() < layout::stack_guard_size();
+    }
+    if va >= THREAD_STACKS_BASE && va < THREAD_STACKS_BASE + THREAD_STACKS_SIZE {
+        return (va - THREAD_STACKS_BASE) % THREAD_STACK_STRIDE < THREAD_STACK_GUARD_SIZE;
+    }
+    false
+}
+
+/// Whether `va` is in the usable part of any kernel stack
+pub fn is_stack(va: usize) -> bool {
+    let in_boot = va >= layout::stacks_start() && va < layout::stacks_end();
+    let in_threads = va >= THREAD_STACKS_BASE && va < THREAD_STACKS_BASE + THREAD_STACKS_SIZE;
+    (in_boot || in_threads) && ! is_guard_page(va)
+}
+
+struct Slots {
+    next: usize,        // slots at and above this have never been used
+    free: Vec<usize>,   // slots below `next` given back
+}
+
+static SLOTS: Lock<Slots> = Lock::new(Slots { next: 0, free: Vec::new() });
+
+/// A kernel thread stack.  It is unmapped and its frames freed on drop.
+pub struct KernelStack {
+    slot: usize,
+}
+
+#[allow(dead_code)]
+impl KernelStack {
+    pub fn new() -> Option<KernelStack> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next < THREAD_STACK_SLOTS => {
+                    slots.next += 1;
+                    slots.next - 1
+                },
+                None => return None,
+            }
+        };
+        let stack = KernelStack { slot: slot };
+
+        let mut kpt = KERNEL_PAGE_TABLE.lock();
+        let pt = kpt.as_mut().expect("Kernel stacks need the kernel page table.\n");
+        let mut va = stack.bottom();
+        while va < stack.top() {
+            // On failure, drop() unmaps and frees the pages mapped so far
+            let pa = frame::alloc_frame()?;
+            if pt.map(va, pa, PteFlags::RW | PteFlags::G).is_err() {
+                frame::free_frame(pa);
+                return None;
+            }
+            va += PAGE_SIZE;
+        }
+        Some(stack)
+    }
+
+    /// The lowest usable address of the stack
+    pub fn bottom(&self) -> usize {
+        THREAD_STACKS_BASE + self.slot * THREAD_STACK_STRIDE + THREAD_STACK_GUARD_SIZE
+    }
+
+    /// The initial stack pointer
+    pub fn top(&self) -> usize {
+        self.bottom() + THREAD_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        {
+            let mut kpt = KERNEL_PAGE_TABLE.lock();
+            let pt = kpt.as_mut().expect("Kernel stacks need the kernel page table.\n");
+            let mut va = self.bottom();
+            while va < self.top() {
+                if let Ok(pa) = pt.unmap(va) {
+                    frame::free_frame(pa);
+                }
+                va += PAGE_SIZE;
+            }
+        }
+        SLOTS.lock().free.push(self.slot);
+    }
+}
@@ -0,0 +1,93 @@
+// Tightly-integrated memory: pages that live in the cache (the L2 LIM on
+// SiFive machines) and so never miss, for kernel structures that want a
+// fixed access time, and for message buffers.
+//
+// A TimPage is owned.  It is freed on drop, and it is what will be handed to
+// a process (as a capability) to map a message buffer into userspace.
+
+use crate::spinlock::Lock;
+use super::{PAGE_SIZE, phys_to_virt};
+
+// Enough for a 2 MiB LIM
+const MAX_TIM_PAGES: usize = 512;
+
+struct TimAllocator {
+    base: usize,        // physical address of the first page
+    pages: usize,
+    free: usize,
+    used: [u64; MAX_TIM_PAGES / 64],
+}
+
+static TIM: Lock<TimAllocator> = Lock::new(TimAllocator {
+    base: 0,
+    pages: 0,
+    free: 0,
+    used: [0; MAX_TIM_PAGES / 64],
+});
+
+/// A page of tightly-integrated memory
+pub struct TimPage {
+    pa: usize,
+}
+
+#[allow(dead_code)]
+impl TimPage {
+    /// Allocate a zeroed page
+    pub fn new() -> Option<TimPage> {
+        let pa = {
+            let mut tim = TIM.lock();
+            let page = (0..tim.pages).find(|&p| tim.used[p / 64] & (1 << (p % 64)) == 0)?;
+            tim.used[page / 64] |= 1 << (page % 64);
+            tim.free -= 1;
+            tim.base + page * PAGE_SIZE
+        };
+        unsafe { core::ptr::write_bytes(phys_to_virt(pa) as *mut u8, 0, PAGE_SIZE) };
+        Some(TimPage { pa: pa })
+    }
+
+    /// Physical address of the page
+    pub fn address(&self) -> usize {
+        self.pa
+    }
+
+    /// The page, through the direct map
+    pub fn as_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.pa) as *mut u8
+    }
+}
+
+impl Drop for TimPage {
+    fn drop(&mut self) {
+        let mut tim = TIM.lock();
+        let page = (self.pa - tim.base) / PAGE_SIZE;
+        tim.used[page / 64] &= !(1 << (page % 64));
+        tim.free += 1;
+    }
+}
+
+/// Take the LIM (if the machine has one) for tightly-integrated memory.  The
+/// kernel page table must be active.
+pub fn init() {
+    let ccache = match crate::target::CCACHE.as_ref() {
+        Some(ccache) => ccache,
+        None => return,
+    };
+    let size = ccache.lim_size().min(MAX_TIM_PAGES * PAGE_SIZE);
+    if size < PAGE_SIZE {
+        return;
+    }
+    crate::target::paging::kernel::add_to_direct_map(ccache.lim_address(), size);
+
+    let mut tim = TIM.lock();
+    tim.base = ccache.lim_address();
+    tim.pages = size / PAGE_SIZE;
+    tim.free = tim.pages;
+}
+
+pub fn free_count() -> usize {
+    TIM.lock().free
+}
+
+pub fn total_count() -> usize {
+    TIM.lock().pages
+}
@@ -0,0 +1,379 @@
+// Address spaces, as a page table plus a list of virtual memory regions that
+// page faults are resolved against.  Anonymous memory is only given frames
+// (zeroed) when first touched, and stack regions grow down on demand to a
+// limit.
+//
+// Anonymous pages can be shared copy-on-write between address spaces: each
+// maps the frame read only with PteFlags::COW, and the first write fault
+// copies it (or, if no other reference is left, just makes it writable).
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::hart::PerHart;
+use crate::spinlock::Spinlock;
+use crate::target::MAX_HARTS;
+use crate::target::paging::{PageTable, PagingError, PteFlags, level_size};
+use crate::target::paging::asid::{self, AsidContext};
+use crate::target::paging::kernel::KERNEL_PAGE_TABLE;
+use super::{frame, PAGE_SIZE, page_round_down, phys_to_virt};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        }
+    }
+
+    // The permission this access needs
+    fn flag(&self) -> PteFlags {
+        match *self {
+            Access::Read => PteFlags::R,
+            Access::Write => PteFlags::W,
+            Access::Execute => PteFlags::X,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    /// Zero-filled on first touch
+    Anonymous,
+    /// Anonymous, and grows down (from its end) to at most `max_size`
+    Stack { max_size: usize },
+}
+
+/// A range of virtual addresses in an address space, from `start` up to (not
+/// including) `end`
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub flags: PteFlags,
+    pub kind: RegionKind,
+    pub name: &'static str,
+}
+
+impl Region {
+    #[inline(always)]
+    pub fn contains(&self, va: usize) -> bool {
+        va >= self.start && va < self.end
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RegionError {
+    /// The start or size was not page aligned, or the size was zero
+    Misaligned,
+    /// The range overlaps another region
+    Overlaps,
+    /// The range is in the kernel's part of the address space
+    Reserved,
+    /// No region starts at the address given
+    NoRegion,
+    /// Mapping the region's pages failed (e.g. no memory for a table)
+    Paging(PagingError),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FaultError {
+    /// This hart has no address space to resolve faults in
+    NoAddressSpace,
+    /// No region contains the address
+    NoRegion,
+    /// The region does not allow the access
+    Protection(Region),
+    /// The stack region would have to grow past its limit (or into another
+    /// region)
+    StackLimit(Region),
+    OutOfMemory,
+    Paging(PagingError),
+}
+
+impl core::fmt::Display for FaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            FaultError::NoAddressSpace => write!(f, "no address space"),
+            FaultError::NoRegion => write!(f, "not in any region"),
+            FaultError::Protection(r) => write!(f, "not allowed in {} {:#x}-{:#x} (flags {:#x})",
+                                                r.name, r.start, r.end, r.flags.bits()),
+            FaultError::StackLimit(r) => write!(f, "beyond the limit of stack {} {:#x}-{:#x}",
+                                                r.name, r.start, r.end),
+            FaultError::OutOfMemory => write!(f, "out of memory"),
+            FaultError::Paging(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+pub struct AddressSpace {
+    pt: PageTable,
+    asid: AsidContext,
+    regions: Vec<Region>,       // sorted by start
+}
+
+#[allow(dead_code)]
+impl AddressSpace {
+    /// Create an address space with no regions, and the kernel's mappings
+    pub fn new() -> Result<AddressSpace, PagingError> {
+        let kpt = KERNEL_PAGE_TABLE.lock();
+        let kernel = kpt.as_ref().expect("The kernel page table is not set up.\n");
+        Ok(AddressSpace {
+            pt: PageTable::new_sharing(kernel)?,
+            asid: AsidContext::new(),
+            regions: Vec::new(),
+        })
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.pt
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// The region containing `va`
+    pub fn find_region(&self, va: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(va))
+    }
+
+    /// Add a region of `size` bytes at `start`.  Nothing is mapped until it
+    /// is touched.  For a stack `start` and `size` are where it starts out,
+    /// and it grows down from there.
+    pub fn add_region(&mut self, start: usize, size: usize, flags: PteFlags, kind: RegionKind,
+                      name: &'static str) -> Result<(), RegionError>
+    {
+        if start % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || size == 0 {
+            return Err(RegionError::Misaligned);
+        }
+        let end = start + size;
+        let lowest = match kind {
+            RegionKind::Stack { max_size } => end.saturating_sub(max_size.max(size)),
+            RegionKind::Anonymous => start,
+        };
+        let mut va = page_round_down(lowest);
+        while va < end {
+            if self.pt.is_shared(va) {
+                return Err(RegionError::Reserved);
+            }
+            va += PAGE_SIZE;
+        }
+        if self.regions.iter().any(|r| r.start < end && start < r.end) {
+            return Err(RegionError::Overlaps);
+        }
+        let region = Region { start: start, end: end, flags: flags, kind: kind, name: name };
+        let at = self.regions.iter().position(|r| r.start > start).unwrap_or(self.regions.len());
+        self.regions.insert(at, region);
+        Ok(())
+    }
+
+    /// Remove the region starting at `start`, freeing the memory it had
+    pub fn remove_region(&mut self, start: usize) -> Option<Region> {
+        let at = self.regions.iter().position(|r| r.start == start)?;
+        let region = self.regions.remove(at);
+        self.unmap(&region);
+        Some(region)
+    }
+
+    fn unmap(&mut self, region: &Region) {
+        let mut va = region.start;
+        while va < region.end {
+            if let Ok(pa) = self.pt.unmap(va) {
+                asid::flush_page(&self.asid, va);
+                frame::free_frame(pa);
+            }
+            va += PAGE_SIZE;
+        }
+    }
+
+    /// Resolve a page fault on an `access` to `va`
+    pub fn handle_fault(&mut self, va: usize, access: Access) -> Result<(), FaultError> {
+        let page = page_round_down(va);
+        let i = match self.regions.iter().position(|r| r.contains(va)) {
+            Some(i) => i,
+            None => self.grow_stack(page)?,
+        };
+        let region = self.regions[i];
+        if ! region.flags.contains(access.flag()) {
+            return Err(FaultError::Protection(region));
+        }
+
+        if let Some((pte, _)) = self.pt.lookup(page) {
+            if access == Access::Write && pte.flags().contains(PteFlags::COW) {
+                return self.break_cow(page, pte.addr(), region.flags);
+            }
+            // Another hart may have got here first, or this hart's TLB was
+            // stale
+            asid::flush_page(&self.asid, page);
+            return Ok(());
+        }
+
+        let pa = frame::alloc_frame_zeroed().ok_or(FaultError::OutOfMemory)?;
+        if let Err(e) = self.pt.map(page, pa, region.flags) {
+            frame::free_frame(pa);
+            return Err(FaultError::Paging(e));
+        }
+        asid::flush_page(&self.asid, page);
+        Ok(())
+    }
+
+    // Give this address space its own writable copy of the shared frame at
+    // `pa` mapped at `page`
+    fn break_cow(&mut self, page: usize, pa: usize, flags: PteFlags) -> Result<(), FaultError> {
+        if frame::frame_refs(pa) == 1 {
+            // Every other sharer has copied it (or gone) already
+            self.pt.protect(page, flags).map_err(FaultError::Paging)?;
+            asid::flush_page(&self.asid, page);
+            return Ok(());
+        }
+        let copy = frame::alloc_frame().ok_or(FaultError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(phys_to_virt(pa) as *const u8,
+                                           phys_to_virt(copy) as *mut u8, PAGE_SIZE);
+        }
+        self.pt.unmap(page).map_err(FaultError::Paging)?;
+        if let Err(e) = self.pt.map(page, copy, flags) {
+            frame::free_frame(copy);
+            return Err(FaultError::Paging(e));
+        }
+        asid::flush_page(&self.asid, page);
+        frame::free_frame(pa);
+        Ok(())
+    }
+
+    /// Share the region starting at `start` into `other` (at the same
+    /// addresses), copy-on-write.  Pages not yet touched stay untouched in
+    /// both.  On an error `other` may have part of the region, and should be
+    /// dropped.
+    pub fn share_region(&mut self, start: usize, other: &mut AddressSpace)
+                        -> Result<(), RegionError>
+    {
+        let region = *self.regions.iter().find(|r| r.start == start)
+            .ok_or(RegionError::NoRegion)?;
+        other.add_region(region.start, region.end - region.start, region.flags, region.kind,
+                         region.name)?;
+
+        let shared_flags = (region.flags & !PteFlags::W) | PteFlags::COW;
+        let mut va = region.start;
+        while va < region.end {
+            if let Some((pte, _)) = self.pt.lookup(va) {
+                if region.flags.contains(PteFlags::W) && ! pte.flags().contains(PteFlags::COW) {
+                    self.pt.protect(va, shared_flags).map_err(RegionError::Paging)?;
+                    asid::flush_page(&self.asid, va);
+                }
+                frame::get_frame(pte.addr());
+                if let Err(e) = other.pt.map(va, pte.addr(), shared_flags) {
+                    // Left unmapped, the child would fault in a zero page
+                    frame::free_frame(pte.addr());
+                    return Err(RegionError::Paging(e));
+                }
+            }
+            va += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Make a copy-on-write copy of this address space (e.g. for cloning a
+    /// process).  On an error the partial copy is dropped, which releases
+    /// what it shared.
+    pub fn fork(&mut self) -> Result<AddressSpace, RegionError> {
+        let mut child = AddressSpace::new().map_err(RegionError::Paging)?;
+        let starts: Vec<usize> = self.regions.iter().map(|r| r.start).collect();
+        for start in starts {
+            self.share_region(start, &mut child)?;
+        }
+        Ok(child)
+    }
+
+    // Grow the stack region just above `page` down to it, returning its index
+    fn grow_stack(&mut self, page: usize) -> Result<usize, FaultError> {
+        let i = self.regions.iter().position(|r| r.start > page).ok_or(FaultError::NoRegion)?;
+        let region = self.regions[i];
+        let max_size = match region.kind {
+            RegionKind::Stack { max_size } => max_size,
+            _ => return Err(FaultError::NoRegion),
+        };
+        if region.end - page > max_size {
+            return Err(FaultError::StackLimit(region));
+        }
+        // Keep at least a page unmapped between the stack and what is below
+        if i > 0 && self.regions[i - 1].end + PAGE_SIZE > page {
+            return Err(FaultError::StackLimit(region));
+        }
+        self.regions[i].start = page;
+        Ok(i)
+    }
+
+    /// Switch this hart to this address space
+    pub fn activate(&self) {
+        asid::switch_to(&self.pt, &self.asid);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let regions = core::mem::replace(&mut self.regions, Vec::new());
+        for region in regions.iter() {
+            self.unmap(region);
+        }
+        asid::flush_context(&self.asid);
+    }
+}
+
+const CURRENT_INIT: Spinlock<Option<Arc<Spinlock<AddressSpace>>>> = Spinlock::new(None);
+static CURRENT: PerHart<Spinlock<Option<Arc<Spinlock<AddressSpace>>>>> =
+    PerHart::new([CURRENT_INIT; MAX_HARTS]);
+
+/// Switch this hart to `space`, which page faults are then resolved against
+#[allow(dead_code)]
+pub fn switch_to(space: Arc<Spinlock<AddressSpace>>) {
+    space.lock().activate();
+    *CURRENT.this_hart().lock() = Some(space);
+}
+
+// This hart's address space
+fn current() -> Option<Arc<Spinlock<AddressSpace>>> {
+    CURRENT.this_hart().lock().clone()
+}
+
+/// Resolve a page fault on this hart (called from the trap handler)
+pub fn handle_fault(va: usize, access: Access) -> Result<(), FaultError> {
+    let space = current().ok_or(FaultError::NoAddressSpace)?;
+    let mut space = space.lock();
+    space.handle_fault(va, access)
+}
+
+/// The end of the user part of this hart's address space (the lower half,
+/// less what is shared with the kernel page table) that `va` is in, or `va`
+/// itself if it is not in the user part
+pub fn user_range_end(va: usize) -> usize {
+    let space = match current() {
+        Some(space) => space,
+        None => return va,
+    };
+    let space = space.lock();
+    let pt = space.page_table();
+    let top = 1 << (pt.mode().va_bits() - 1);
+    let slot = level_size(pt.mode().levels() - 1);
+    let mut end = va;
+    while end < top && ! pt.is_shared(end) {
+        end = (end / slot + 1) * slot;
+    }
+    end.min(top).max(va)
+}
+
+/// Whether `len` bytes at `va` are all in the user part of this hart's
+/// address space
+pub fn is_user_range(va: usize, len: usize) -> bool {
+    let end = user_range_end(va);
+    end > va && va.checked_add(len).map_or(false, |last| last <= end)
+}
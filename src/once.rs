@@ -0,0 +1,116 @@
+// One-time initialization of globals at run time, for things (like devices)
+// that can't be built by a const fn, or shouldn't be built until the kernel
+// knows enough (e.g. has read the device tree).  After that they are used
+// through shared references, so they needn't be `static mut`.
+
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use crate::atomic::{Atomic, AtomicUSize};
+use crate::target::fence;
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+/// A value set at most once.  Harts that want it while another is setting it
+/// wait (so the initializer must not itself need the value).
+pub struct Once<T> {
+    state: AtomicUSize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Once<T> {
+    #[allow(dead_code)]
+    pub const fn new() -> Once<T> {
+        Once {
+            state: AtomicUSize::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, setting it with `f` if this is the first call
+    #[allow(dead_code)]
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if self.state.fetch() == COMPLETE {
+            // fetch() is a plain load, so order reading the value after it
+            fence();
+        } else if self.state.compare_and_swap(INCOMPLETE, RUNNING) == INCOMPLETE {
+            unsafe { (*self.value.get()).as_mut_ptr().write(f()) };
+            self.state.store_rel(COMPLETE);
+        } else {
+            while self.state.fetch() != COMPLETE {
+                crate::target::pause();
+            }
+            fence();
+        }
+        unsafe { &*(*self.value.get()).as_ptr() }
+    }
+
+    /// The value, if it has been set
+    #[allow(dead_code)]
+    pub fn get(&self) -> Option<&T> {
+        if self.state.fetch() == COMPLETE {
+            fence();
+            Some(unsafe { &*(*self.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the value has been set
+    #[allow(dead_code)]
+    pub fn is_completed(&self) -> bool {
+        self.state.fetch() == COMPLETE
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if self.state.fetch() == COMPLETE {
+            unsafe { core::ptr::drop_in_place((*self.value.get()).as_mut_ptr()) };
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+unsafe impl<T: Send> Send for Once<T> {}
+
+
+/// A value built by `init` the first time it is used
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+impl<T, F> Lazy<T, F> {
+    #[allow(dead_code)]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Build the value now, if it hasn't been already
+    #[allow(dead_code)]
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        // Only the one hart that runs the closure takes `init`
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => panic!("Lazy value's initializer was lost.\n"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
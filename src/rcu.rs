@@ -0,0 +1,264 @@
+// Read-copy-update, for data that is read far more than it is changed
+// (routing tables, capability lookup, service registries).
+//
+// Readers take no locks: they hold an RcuReadGuard (rcu_read_lock()) while
+// they use RCU-protected data, and must not block or switch context while
+// they do.  Writers publish a new version with RcuPtr and free the old one
+// once every reader that could have seen it is done, which is a "grace
+// period": every online hart has passed through a quiescent state (a point
+// outside any read-side section, reported by quiescent_state() from the
+// idle loop, and from context switches once there is a scheduler).  Offline
+// harts are always quiescent.
+//
+// Grace periods are numbered.  Starting one bumps GRACE_PERIOD, and it is
+// over once every online hart's `seen` has caught up with its number.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use crate::atomic::{Atomic, AtomicBool, AtomicU64, AtomicUSize};
+use crate::hart::PerHart;
+use crate::spinlock::Spinlock;
+use crate::target::{AtomicPtr, MAX_HARTS, fence};
+
+struct HartRcu {
+    online: AtomicBool,
+    nesting: AtomicUSize,       // read-side sections this hart is in
+    seen: AtomicU64,            // the last grace period this hart was quiescent in
+}
+
+const HART_RCU_INIT: HartRcu = HartRcu {
+    online: AtomicBool::new(false),
+    nesting: AtomicUSize::new(0),
+    seen: AtomicU64::new(0),
+};
+
+static HARTS: PerHart<HartRcu> = PerHart::new([HART_RCU_INIT; MAX_HARTS]);
+
+static GRACE_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+struct Callback {
+    grace_period: u64,
+    f: Box<dyn FnOnce() + Send>,
+}
+
+static CALLBACKS: Spinlock<Vec<Callback>> = Spinlock::new(Vec::new());
+
+/// This hart starts taking part in grace periods.  It must not be in a
+/// read-side section.
+pub fn hart_online() {
+    let rcu = HARTS.this_hart();
+    rcu.seen.store(GRACE_PERIOD.fetch());
+    rcu.online.store_seqcst(true);
+}
+
+/// This hart stops taking part (e.g. before it is parked)
+#[allow(dead_code)]
+pub fn hart_offline() {
+    HARTS.this_hart().online.store_seqcst(false);
+}
+
+/// Report that this hart holds no references to RCU-protected data (it is
+/// between read-side sections), and run any callbacks whose grace period is
+/// over.  Called from the idle loop (idle() in kernel.rs), and to be called
+/// on context switch once there is a scheduler.
+pub fn quiescent_state() {
+    let rcu = HARTS.this_hart();
+    if rcu.nesting.fetch() != 0 {
+        return;
+    }
+    // Our reads of protected data are done before others see this
+    fence();
+    rcu.seen.store_rel(GRACE_PERIOD.fetch());
+    // And our later reads don't pass it
+    fence();
+    run_callbacks();
+}
+
+// Start a new grace period, returning its number
+fn start_grace_period() -> u64 {
+    fence();
+    GRACE_PERIOD.fetch_add(1) + 1
+}
+
+// Whether grace period `gp` is over
+fn completed(gp: u64) -> bool {
+    HARTS.iter().all(|(_, rcu)| ! rcu.online.fetch() || rcu.seen.fetch() >= gp)
+}
+
+fn run_callbacks() {
+    let ready: Vec<Callback> = {
+        let mut callbacks = CALLBACKS.lock();
+        if callbacks.is_empty() {
+            return;
+        }
+        let (ready, waiting): (Vec<Callback>, Vec<Callback>) =
+            callbacks.drain(..).partition(|cb| completed(cb.grace_period));
+        *callbacks = waiting;
+        ready
+    };
+    for cb in ready {
+        (cb.f)();
+    }
+}
+
+/// Wait until every read-side section that had started has finished.  Must
+/// not be called inside one.
+#[allow(dead_code)]
+pub fn synchronize_rcu() {
+    if HARTS.this_hart().nesting.fetch() != 0 {
+        panic!("synchronize_rcu() in an RCU read-side section.\n");
+    }
+    let gp = start_grace_period();
+    quiescent_state();
+    while ! completed(gp) {
+        crate::target::pause();
+    }
+    fence();
+}
+
+/// Run `f` once every read-side section that had started has finished (e.g.
+/// to free the old version of something).  It runs from quiescent_state() on
+/// some hart, so it should be quick.
+#[allow(dead_code)]
+pub fn call_rcu<F: FnOnce() + Send + 'static>(f: F) {
+    let gp = start_grace_period();
+    CALLBACKS.lock().push(Callback { grace_period: gp, f: Box::new(f) });
+}
+
+/// Publish, read and replace an RcuPtr, and run a callback, checking that
+/// whole pointers go through.  Run at boot on the boot hart, once the heap is
+/// up and it is online.
+pub fn check() {
+    let ptr: RcuPtr<u64> = RcuPtr::null();
+    let first = Box::new(1);
+    let first_at = &*first as *const u64 as usize;
+    ptr.assign(Some(first));
+    let seen = {
+        let guard = rcu_read_lock();
+        ptr.read(&guard).map_or(0, |v| v as *const u64 as usize)
+    };
+    if seen != first_at {
+        println!("RCU: published {:#x} but read {:#x}", first_at, seen);
+        panic!("RcuPtr read a different pointer than was published.\n");
+    }
+    match ptr.replace(Some(Box::new(2))) {
+        Some(old) if &*old as *const u64 as usize == first_at && *old == 1 => { },
+        _ => panic!("RcuPtr::replace() didn't return the old version.\n"),
+    }
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+    call_rcu(|| RAN.store(true));
+    quiescent_state();
+    if ! RAN.fetch() {
+        panic!("call_rcu() callback didn't run after a grace period.\n");
+    }
+}
+
+/// Mark this hart as reading RCU-protected data until the guard is dropped.
+/// Read-side sections nest.
+#[allow(dead_code)]
+pub fn rcu_read_lock() -> RcuReadGuard {
+    HARTS.this_hart().nesting.fetch_add(1);
+    RcuReadGuard { not_send: PhantomData }
+}
+
+/// A read-side section, which stays on the hart that started it
+pub struct RcuReadGuard {
+    not_send: PhantomData<*const ()>,
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        // The section's reads are done before the count drops
+        fence();
+        HARTS.this_hart().nesting.fetch_sub(1);
+    }
+}
+
+
+/// A pointer to RCU-protected data, or to nothing.  Readers get references
+/// that last as long as their RcuReadGuard.  Writers must be serialized by
+/// the user (e.g. with a Spinlock), and each replaced version is freed once
+/// no reader can still be using it.
+#[allow(dead_code)]
+pub struct RcuPtr<T: Send + Sync + 'static> {
+    ptr: UnsafeCell<usize>,     // a Box<T>, or 0
+    owns: PhantomData<Box<T>>,
+}
+
+impl<T: Send + Sync + 'static> RcuPtr<T> {
+    /// A pointer to nothing
+    #[allow(dead_code)]
+    pub const fn null() -> RcuPtr<T> {
+        RcuPtr {
+            ptr: UnsafeCell::new(0),
+            owns: PhantomData,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn new(value: Box<T>) -> RcuPtr<T> {
+        RcuPtr {
+            ptr: UnsafeCell::new(Box::into_raw(value) as usize),
+            owns: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    fn atomic(&self) -> AtomicPtr<usize> {
+        unsafe { AtomicPtr::<usize>::new(self.ptr.get()) }
+    }
+
+    /// The current version, for as long as the read-side section lasts
+    #[allow(dead_code)]
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> Option<&'a T> {
+        let ptr = self.atomic().fetch() as *const T;
+        unsafe { ptr.as_ref() }
+    }
+
+    /// Publish `value` (or nothing), and return the old version once no
+    /// reader can still be using it.  Waits for a grace period.
+    #[allow(dead_code)]
+    pub fn replace(&self, value: Option<Box<T>>) -> Option<Box<T>> {
+        let old = self.swap(value);
+        if old != 0 {
+            synchronize_rcu();
+            Some(unsafe { Box::from_raw(old as *mut T) })
+        } else {
+            None
+        }
+    }
+
+    /// Publish `value` (or nothing), and free the old version after a grace
+    /// period, without waiting for it
+    #[allow(dead_code)]
+    pub fn assign(&self, value: Option<Box<T>>) {
+        let old = self.swap(value);
+        if old != 0 {
+            call_rcu(move || drop(unsafe { Box::from_raw(old as *mut T) }));
+        }
+    }
+
+    // Publish `value`, returning the old pointer.  The sequentially
+    // consistent swap orders the writes that built `value` before it.
+    fn swap(&self, value: Option<Box<T>>) -> usize {
+        let new = value.map_or(0, |v| Box::into_raw(v) as usize);
+        self.atomic().swap_seqcst(new)
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for RcuPtr<T> {
+    // Nobody can be reading it: readers borrow it
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        if ptr != 0 {
+            drop(unsafe { Box::from_raw(ptr as *mut T) });
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync + 'static> Sync for RcuPtr<T> {}
+
+unsafe impl<T: Send + Sync + 'static> Send for RcuPtr<T> {}
@@ -3,6 +3,19 @@ use crate::target::AtomicPtr;
 use crate::atomic::Atomic;
 use crate::spinlock::Spinlock;
 
+// The registers here are reached through the MMIO map (see
+// memory::mmio_phys_to_virt()), where the kernel page table maps them with the
+// IO memory type: the machine's MMIO_REGIONS, and anything passed to
+// map_device().
+
+/// Make the device registers at physical address `pa` reachable by the
+/// register types here, returning their virtual address
+#[allow(dead_code)]
+pub fn map_device(pa: usize, size: usize) -> usize {
+    crate::target::paging::kernel::map_mmio(pa, size)
+        .expect("Could not map device registers.\n")
+}
+
 macro_rules! impl_atomic_register_ro {
     ($typ:ident, $inner:ty) => (
         pub struct $typ(AtomicPtr<$inner>);
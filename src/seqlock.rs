@@ -0,0 +1,218 @@
+// A sequence lock, for small Copy data that is read far more often than it is
+// written (timekeeping, statistics, clock rates).  Readers take no lock and
+// never make a writer wait; they copy the data and retry if a write happened
+// meanwhile.  Writers are serialized by the sequence number itself.
+//
+// Why a reader can't return a torn copy
+// -------------------------------------
+// The sequence number `seq` is odd exactly while a write is in progress.  A
+// writer does
+//
+//   W1  seq: 2n -> 2n+1        compare_and_swap, sequentially consistent
+//   W2  fence()
+//   W3  write the data
+//   W4  seq = 2n+2             store_rel
+//
+// and a reader does
+//
+//   R1  s1 = seq               fetch (a plain load); retry if odd
+//   R2  fence()
+//   R3  copy the data
+//   R4  fence()
+//   R5  s2 = seq               fetch; retry if s2 != s1
+//
+// fence() is a compiler barrier as well as a `fence`, so the plain loads at
+// R1 and R5 stay where they are.
+//
+// Suppose the reader returns (s1 == s2 == 2n, even) but R3 saw some of a
+// writer's W3.  That writer took seq from 2n to 2n+1 at W1 (no other writer
+// can have moved seq away from 2n in between, as s1 == s2 and seq only
+// grows).  W2 orders W1 before W3, and R4 orders R3 before R5, so R3 seeing
+// W3 means R5 happens after W1 and reads at least 2n+1.  That contradicts
+// s2 == 2n.
+//
+// Conversely if the copy at R3 could be missing part of the write that made
+// seq 2n (read a value from before W3), R1 read 2n from W4.  The release of
+// W4 orders W3 before it, and R2 orders R1 before R3, so R3 sees all of W3.
+//
+// So a returned copy is exactly the data as some writer left it.  A writer
+// that stops between W1 and W4 stalls readers until it finishes, so writers
+// shouldn't be interrupted by code that reads the same SeqLock.
+
+use core::cell::UnsafeCell;
+use crate::atomic::{Atomic, AtomicU64};
+use crate::target::fence;
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    #[allow(dead_code)]
+    pub const fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicU64::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// A consistent copy of the data
+    #[allow(dead_code)]
+    pub fn read(&self) -> T {
+        loop {
+            let s1 = self.seq.fetch();
+            if s1 & 1 != 0 {
+                crate::target::pause();
+                continue;
+            }
+            fence();
+            // Racing with a writer is expected here; volatile keeps the
+            // compiler from assuming otherwise, and the result is thrown
+            // away unless seq shows there was no writer
+            let data = unsafe { core::ptr::read_volatile(self.data.get()) };
+            fence();
+            if self.seq.fetch() == s1 {
+                return data;
+            }
+        }
+    }
+
+    /// Replace the data
+    #[allow(dead_code)]
+    pub fn write(&self, data: T) {
+        self.update(|d| *d = data);
+    }
+
+    /// Change the data in place with `f`.  Readers retry until it returns,
+    /// so it should be short.
+    #[allow(dead_code)]
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        let mut seq;
+        loop {
+            seq = self.seq.fetch();
+            if seq & 1 == 0 && self.seq.compare_and_swap(seq, seq + 1) == seq {
+                break;
+            }
+            crate::target::pause();
+        }
+        fence();
+        f(unsafe { &mut *self.data.get() });
+        self.seq.store_rel(seq + 2);
+    }
+
+    /// How many writes there have been
+    #[allow(dead_code)]
+    pub fn sequence(&self) -> u64 {
+        self.seq.fetch() / 2
+    }
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+// A model of the proof above.  The fences keep each side's steps in program
+// order, so trying every interleaving of a reader with two writes covers
+// what the harts can do.  The data is two words, written and copied one at a
+// time, so a torn copy can be seen.  Dropping any one fence lets a step move
+// past it, and the model then finds a torn copy the reader would return.
+#[cfg(test)]
+mod tests {
+    // A step on seq (W1 and W4 of a writer, R1 and R5 of a reader), or on
+    // one word of the data (W3, R3)
+    #[derive(Clone, Copy)]
+    enum Step { Seq, A, B }
+
+    #[derive(Clone, Copy, Default)]
+    struct Memory {
+        seq: usize,
+        a: usize,
+        b: usize,
+    }
+
+    // What the reader has loaded so far
+    #[derive(Clone, Copy, Default)]
+    struct Snapshot {
+        seqs: usize,
+        s1: usize,
+        s2: usize,
+        a: usize,
+        b: usize,
+    }
+
+    const WRITER: [Step; 4] = [Step::Seq, Step::A, Step::B, Step::Seq];
+    const READER: [Step; 4] = [Step::Seq, Step::A, Step::B, Step::Seq];
+
+    // Whether some interleaving of `writes` writes (each doing `writer`, and
+    // write n storing n in both words) with a reader doing `reader` has the
+    // reader accept a copy that isn't what write s1 / 2 left
+    fn torn(writer: &[Step], writes: usize, reader: &[Step]) -> bool {
+        explore(Memory::default(), writer, 0, writes * writer.len(), reader, 0,
+                Snapshot::default())
+    }
+
+    fn explore(mem: Memory, writer: &[Step], w: usize, w_end: usize,
+               reader: &[Step], r: usize, snap: Snapshot) -> bool {
+        if r == reader.len() {
+            return snap.s1 & 1 == 0 && snap.s1 == snap.s2
+                && (snap.a != snap.s1 / 2 || snap.b != snap.s1 / 2);
+        }
+
+        // The reader goes next
+        let mut next = snap;
+        match reader[r] {
+            Step::Seq => {
+                if next.seqs == 0 { next.s1 = mem.seq; } else { next.s2 = mem.seq; }
+                next.seqs += 1;
+            },
+            Step::A => next.a = mem.a,
+            Step::B => next.b = mem.b,
+        }
+        if explore(mem, writer, w, w_end, reader, r + 1, next) {
+            return true;
+        }
+
+        // Or the writer does
+        if w < w_end {
+            let mut mem = mem;
+            let write = w / writer.len() + 1;
+            match writer[w % writer.len()] {
+                Step::Seq => mem.seq += 1,
+                Step::A => mem.a = write,
+                Step::B => mem.b = write,
+            }
+            return explore(mem, writer, w + 1, w_end, reader, r, snap);
+        }
+        false
+    }
+
+    #[test]
+    fn reader_never_returns_a_torn_copy() {
+        assert!(! torn(&WRITER, 2, &READER));
+    }
+
+    #[test]
+    fn torn_without_w2() {
+        // W3 moves before W1
+        assert!(torn(&[Step::A, Step::Seq, Step::B, Step::Seq], 2, &READER));
+    }
+
+    #[test]
+    fn torn_without_release_at_w4() {
+        // W3 moves after W4
+        assert!(torn(&[Step::Seq, Step::A, Step::Seq, Step::B], 2, &READER));
+    }
+
+    #[test]
+    fn torn_without_r2() {
+        // R3 moves before R1
+        assert!(torn(&WRITER, 2, &[Step::A, Step::Seq, Step::B, Step::Seq]));
+    }
+
+    #[test]
+    fn torn_without_r4() {
+        // R3 moves after R5
+        assert!(torn(&WRITER, 2, &[Step::Seq, Step::A, Step::Seq, Step::B]));
+    }
+}
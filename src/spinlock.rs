@@ -1,18 +1,68 @@
 
 use core::cell::UnsafeCell;
 use core::ops::{Drop, Deref, DerefMut};
-use crate::atomic::{Atomic, AtomicBool};
+use crate::atomic::{Atomic, AtomicBool, AtomicU64, AtomicUSize};
+use crate::lockdep;
+#[cfg(lock_stats)]
+use crate::lock_stats;
+
+// Tell lockdep about a lock of data of type T (its class)
+#[inline(always)]
+fn lockdep_acquire<T: ?Sized, L: ?Sized>(lock: &L) {
+    lockdep::acquire(core::any::type_name::<T>(), lock as *const L as *const u8 as usize);
+}
+
+#[inline(always)]
+fn lockdep_release<L: ?Sized>(lock: &L) {
+    lockdep::release(lock as *const L as *const u8 as usize);
+}
 
 pub struct Spinlock<T: ?Sized> {
     locked: AtomicBool,
+    #[cfg(debug_assertions)]
+    owner: Owner,
     data: UnsafeCell<T>
 }
 
+// Who holds a Spinlock, in debug builds
+#[cfg(debug_assertions)]
+struct Owner {
+    hart: AtomicUSize,          // the holder's cpu_number() + 1, or 0
+    site: AtomicUSize,          // the &'static Location it was locked at
+}
+
+// How many cycles Spinlock::lock() spins before reporting a possible deadlock
+#[cfg(debug_assertions)]
+static SPIN_TIMEOUT: AtomicU64 = AtomicU64::new(1_000_000_000);
+
+// Which harts are printing a deadlock report.  println!() takes the console's
+// Spinlock, which may be the one we're stuck on, so a report must not lead to
+// another.
+#[cfg(debug_assertions)]
+static REPORTING: [AtomicBool; crate::target::MAX_HARTS] =
+    [REPORTING_INIT; crate::target::MAX_HARTS];
+#[cfg(debug_assertions)]
+const REPORTING_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Take the `spin_timeout=<cycles>` command line option, for debug builds'
+/// Spinlock deadlock reports
+pub fn init() {
+    #[cfg(debug_assertions)]
+    if let Some(timeout) = crate::cmdline::get("spin_timeout").and_then(|t| t.parse().ok()) {
+        SPIN_TIMEOUT.store(timeout);
+    }
+}
+
 impl<T> Spinlock<T> {
     #[allow(dead_code)]
     pub const fn new(data: T) -> Spinlock<T> {
         Spinlock {
             locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: Owner {
+                hart: AtomicUSize::new(0),
+                site: AtomicUSize::new(0),
+            },
             data: UnsafeCell::new(data)
         }
     }
@@ -29,17 +79,68 @@ impl<T> Spinlock<T> {
 impl<T: ?Sized> Spinlock<T> {
     /// Lock and return a guard
     #[allow(dead_code)]
+    #[cfg_attr(debug_assertions, track_caller)]
     pub fn lock(&self) -> SpinlockGuard<T> {
+        lockdep_acquire::<T, _>(self);
+        #[cfg(debug_assertions)]
+        let (me, site) = (crate::target::cpu_number() as usize + 1,
+                          core::panic::Location::caller());
+        #[cfg(debug_assertions)]
+        if self.owner.hart.fetch() == me {
+            println!("Spinlock at {:p} relocked by hart {} at {}, already locked at {}",
+                     self, me - 1, site, self.owner_site());
+            panic!("Spinlock relocked by its holder\n");
+        }
+        #[cfg(debug_assertions)]
+        let mut deadline = crate::target::cycles().saturating_add(SPIN_TIMEOUT.fetch());
+        #[cfg(lock_stats)]
+        let (spin_start, mut contended) = (crate::target::cycles(), false);
         let mut previous_value;
         loop {
             previous_value = self.locked.compare_and_swap(false, true);
             if previous_value == false { break; }
+            #[cfg(lock_stats)]
+            { contended = true; }
+            #[cfg(debug_assertions)]
+            if crate::target::cycles() > deadline {
+                self.report_timeout(me, site);
+                deadline = u64::MAX;
+            }
             crate::target::pause();
         }
+        #[cfg(debug_assertions)]
+        {
+            self.owner.hart.store(me);
+            self.owner.site.store(site as *const core::panic::Location as usize);
+        }
         SpinlockGuard {
             spinlock: &self,
-            data: unsafe { &mut *self.data.get() }
+            data: unsafe { &mut *self.data.get() },
+            #[cfg(lock_stats)]
+            hold: lock_stats::acquired(core::any::type_name::<T>(), spin_start, contended),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn report_timeout(&self, me: usize, site: &core::panic::Location) {
+        let reporting = &REPORTING[me - 1];
+        if reporting.fetch() {
+            return;
+        }
+        reporting.store(true);
+        println!("Spinlock at {:p}: hart {} has waited {} cycles at {}, possible deadlock",
+                 self, me - 1, SPIN_TIMEOUT.fetch(), site);
+        match self.owner.hart.fetch() {
+            0 => println!("  (holder unknown)"),
+            hart => println!("  held by hart {}, locked at {}", hart - 1, self.owner_site()),
         }
+        reporting.store(false);
+    }
+
+    // Where the holder locked it
+    #[cfg(debug_assertions)]
+    fn owner_site(&self) -> OwnerSite {
+        OwnerSite(self.owner.site.fetch() as *const core::panic::Location<'static>)
     }
 
     /// Break through the lock and get the mutable data anyways.  This is
@@ -58,6 +159,20 @@ impl<T: ?Sized> Spinlock<T> {
     }
 }
 
+// A Spinlock holder's locking site, which may be changing under us
+#[cfg(debug_assertions)]
+struct OwnerSite(*const core::panic::Location<'static>);
+
+#[cfg(debug_assertions)]
+impl core::fmt::Display for OwnerSite {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match unsafe { self.0.as_ref() } {
+            Some(site) => write!(f, "{}", site),
+            None => write!(f, "(unknown)"),
+        }
+    }
+}
+
 unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
 
 unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
@@ -66,6 +181,8 @@ unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
 pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
     spinlock: &'a Spinlock<T>,
     data: &'a mut T,
+    #[cfg(lock_stats)]
+    hold: lock_stats::Hold,
 }
 
 impl<'a, T: ?Sized> Deref for SpinlockGuard<'a, T> {
@@ -85,6 +202,485 @@ impl<'a, T: ?Sized> Drop for SpinlockGuard<'a, T> {
                 panic!("lock dropped, but not locked!!!");
             }
         }
+        #[cfg(debug_assertions)]
+        self.spinlock.owner.hart.store(0);
+        #[cfg(lock_stats)]
+        lock_stats::released(&self.hold);
         self.spinlock.locked.store_rel(false);
+        lockdep_release(self.spinlock);
     }
 }
+
+
+/// A fair spinlock.  Each locker takes a ticket and waits for its number to be
+/// served, so harts get the lock in the order they asked for it, instead of
+/// whoever wins the compare_and_swap() race as with Spinlock.
+pub struct TicketSpinlock<T: ?Sized> {
+    // Both 64 bits, so the AMOs on them and the plain loads agree on the
+    // width and a ticket never wraps
+    next_ticket: AtomicU64,
+    now_serving: AtomicU64,
+    data: UnsafeCell<T>
+}
+
+impl<T> TicketSpinlock<T> {
+    #[allow(dead_code)]
+    pub const fn new(data: T) -> TicketSpinlock<T> {
+        TicketSpinlock {
+            next_ticket: AtomicU64::new(0),
+            now_serving: AtomicU64::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        let TicketSpinlock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketSpinlock<T> {
+    /// Lock and return a guard
+    #[allow(dead_code)]
+    pub fn lock(&self) -> TicketSpinlockGuard<T> {
+        lockdep_acquire::<T, _>(self);
+        let ticket = self.next_ticket.fetch_add(1);
+        while self.now_serving.fetch() != ticket {
+            crate::target::pause();
+        }
+        // fetch() is a plain load, so order the critical section after the
+        // one that saw our ticket served
+        crate::target::fence();
+        TicketSpinlockGuard {
+            spinlock: &self,
+            data: unsafe { &mut *self.data.get() }
+        }
+    }
+
+    /// Break through the lock and get the mutable data anyways.  See
+    /// Spinlock::breaklock().
+    #[allow(dead_code)]
+    pub unsafe fn breaklock(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
+    /// Get whether it is locked. Unsafe as the result cannot be relied upon
+    /// as it is not synchronzied in any way.
+    #[allow(dead_code)]
+    pub unsafe fn is_locked(&self) -> bool {
+        self.next_ticket.fetch() != self.now_serving.fetch()
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for TicketSpinlock<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for TicketSpinlock<T> {}
+
+
+pub struct TicketSpinlockGuard<'a, T: ?Sized + 'a> {
+    spinlock: &'a TicketSpinlock<T>,
+    data: &'a mut T,
+}
+
+impl<'a, T: ?Sized> Deref for TicketSpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { &*self.data }
+}
+
+impl<'a, T: ?Sized> DerefMut for TicketSpinlockGuard<'a, T> {
+    fn deref_mut<'b>(&'b mut self) -> &'b mut T { &mut *self.data }
+}
+
+impl<'a, T: ?Sized> Drop for TicketSpinlockGuard<'a, T> {
+    /// Serve the next ticket.  Only the holder writes now_serving, so this
+    /// needn't be a read-modify-write.
+    fn drop(&mut self) {
+        let serving = self.spinlock.now_serving.fetch();
+        if cfg!(debug_assertions) {
+            if self.spinlock.next_ticket.fetch() == serving {
+                panic!("lock dropped, but not locked!!!");
+            }
+        }
+        self.spinlock.now_serving.store_rel(serving.wrapping_add(1));
+        lockdep_release(self.spinlock);
+    }
+}
+
+
+/// A waiter's place in an McsSpinlock queue.  It is usually on the locker's
+/// stack, and must stay put (which the guard's borrow ensures) until unlock.
+pub struct McsNode {
+    next: AtomicUSize,
+    locked: AtomicBool,
+}
+
+impl McsNode {
+    #[allow(dead_code)]
+    pub const fn new() -> McsNode {
+        McsNode {
+            next: AtomicUSize::new(0),
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+/// An MCS queue lock.  Waiters queue up behind `tail`, each spinning on its
+/// own McsNode, so a hand over touches only the next waiter's cache line
+/// rather than every waiter's as with Spinlock.
+pub struct McsSpinlock<T: ?Sized> {
+    tail: AtomicUSize,          // the last node in the queue, or 0 if unlocked
+    data: UnsafeCell<T>
+}
+
+impl<T> McsSpinlock<T> {
+    #[allow(dead_code)]
+    pub const fn new(data: T) -> McsSpinlock<T> {
+        McsSpinlock {
+            tail: AtomicUSize::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        let McsSpinlock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> McsSpinlock<T> {
+    /// Lock, queueing on `node`, and return a guard
+    #[allow(dead_code)]
+    pub fn lock<'a>(&'a self, node: &'a mut McsNode) -> McsSpinlockGuard<'a, T> {
+        lockdep_acquire::<T, _>(self);
+        let me = node as *mut McsNode as usize;
+        node.next.store(0);
+        node.locked.store(true);
+        let prev = self.tail.swap_seqcst(me);
+        if prev != 0 {
+            // Join the queue, and wait for our predecessor to hand over
+            unsafe { (*(prev as *const McsNode)).next.store_rel(me) };
+            while node.locked.fetch() {
+                crate::target::pause();
+            }
+            // Order the critical section after the plain load that saw the
+            // hand over
+            crate::target::fence();
+        }
+        McsSpinlockGuard {
+            spinlock: &self,
+            node: node,
+            data: unsafe { &mut *self.data.get() }
+        }
+    }
+
+    /// Break through the lock and get the mutable data anyways.  See
+    /// Spinlock::breaklock().
+    #[allow(dead_code)]
+    pub unsafe fn breaklock(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
+    /// Get whether it is locked. Unsafe as the result cannot be relied upon
+    /// as it is not synchronzied in any way.
+    #[allow(dead_code)]
+    pub unsafe fn is_locked(&self) -> bool {
+        self.tail.fetch() != 0
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for McsSpinlock<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for McsSpinlock<T> {}
+
+
+pub struct McsSpinlockGuard<'a, T: ?Sized + 'a> {
+    spinlock: &'a McsSpinlock<T>,
+    node: &'a mut McsNode,
+    data: &'a mut T,
+}
+
+impl<'a, T: ?Sized> Deref for McsSpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { &*self.data }
+}
+
+impl<'a, T: ?Sized> DerefMut for McsSpinlockGuard<'a, T> {
+    fn deref_mut<'b>(&'b mut self) -> &'b mut T { &mut *self.data }
+}
+
+impl<'a, T: ?Sized> Drop for McsSpinlockGuard<'a, T> {
+    /// Hand the lock to the next waiter, or unlock it if there is none.
+    fn drop(&mut self) {
+        lockdep_release(self.spinlock);
+        let me = &*self.node as *const McsNode as usize;
+        let mut next = self.node.next.fetch();
+        if next == 0 {
+            // Nobody queued behind us, unless one is between its swap of
+            // tail and linking itself to us
+            if self.spinlock.tail.compare_and_swap(me, 0) == me {
+                return;
+            }
+            loop {
+                next = self.node.next.fetch();
+                if next != 0 { break; }
+                crate::target::pause();
+            }
+            // See the successor's node as it was when it linked itself in
+            crate::target::fence();
+        }
+        unsafe { (*(next as *const McsNode)).locked.store_rel(false) };
+    }
+}
+
+
+// RwSpinlock state bits.  The rest of the word counts readers.
+const RW_WRITER: usize = 1;             // a writer holds the lock
+const RW_UPGRADEABLE: usize = 2;        // an upgradeable reader holds the lock
+const RW_WRITER_WAITING: usize = 4;     // keep new readers out
+const RW_READER: usize = 8;             // one reader
+
+/// A reader-writer spinlock, for read-mostly data.  Any number of readers, or
+/// one writer, hold it at once.  Waiting writers keep new readers out so they
+/// aren't starved.  One of the readers may take an upgradeable read, which it
+/// can later turn into a write without letting a writer in between.
+pub struct RwSpinlock<T: ?Sized> {
+    state: AtomicUSize,
+    data: UnsafeCell<T>
+}
+
+impl<T> RwSpinlock<T> {
+    #[allow(dead_code)]
+    pub const fn new(data: T) -> RwSpinlock<T> {
+        RwSpinlock {
+            state: AtomicUSize::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        let RwSpinlock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    /// Lock for reading and return a guard
+    #[allow(dead_code)]
+    pub fn read(&self) -> RwSpinlockReadGuard<T> {
+        lockdep_acquire::<T, _>(self);
+        loop {
+            let state = self.state.fetch();
+            if state & (RW_WRITER | RW_WRITER_WAITING) == 0
+                && self.state.compare_and_swap(state, state + RW_READER) == state {
+                break;
+            }
+            crate::target::pause();
+        }
+        RwSpinlockReadGuard {
+            spinlock: &self,
+            data: unsafe { &*self.data.get() }
+        }
+    }
+
+    /// Lock for reading, with the option to upgrade to writing later, and
+    /// return a guard.  Other readers may come and go, but only one
+    /// upgradeable reader holds the lock at a time.
+    #[allow(dead_code)]
+    pub fn upgradeable_read(&self) -> RwSpinlockUpgradeableGuard<T> {
+        lockdep_acquire::<T, _>(self);
+        loop {
+            let state = self.state.fetch();
+            if state & (RW_WRITER | RW_UPGRADEABLE | RW_WRITER_WAITING) == 0
+                && self.state.compare_and_swap(state, state | RW_UPGRADEABLE) == state {
+                break;
+            }
+            crate::target::pause();
+        }
+        RwSpinlockUpgradeableGuard {
+            spinlock: &self,
+            data: unsafe { &*self.data.get() }
+        }
+    }
+
+    /// Lock for writing and return a guard
+    #[allow(dead_code)]
+    pub fn write(&self) -> RwSpinlockWriteGuard<T> {
+        lockdep_acquire::<T, _>(self);
+        loop {
+            let state = self.state.fetch();
+            if state & !RW_WRITER_WAITING == 0 {
+                // Taking it clears WRITER_WAITING, other waiting writers set
+                // it again
+                if self.state.compare_and_swap(state, RW_WRITER) == state {
+                    break;
+                }
+            } else if state & RW_WRITER_WAITING == 0 {
+                self.state.fetch_or(RW_WRITER_WAITING);
+            }
+            crate::target::pause();
+        }
+        RwSpinlockWriteGuard {
+            spinlock: &self,
+            data: unsafe { &mut *self.data.get() }
+        }
+    }
+
+    /// Break through the lock and get the mutable data anyways.  See
+    /// Spinlock::breaklock().
+    #[allow(dead_code)]
+    pub unsafe fn breaklock(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
+    /// Get whether it is locked (for reading or writing). Unsafe as the result
+    /// cannot be relied upon as it is not synchronzied in any way.
+    #[allow(dead_code)]
+    pub unsafe fn is_locked(&self) -> bool {
+        self.state.fetch() & !RW_WRITER_WAITING != 0
+    }
+
+    // Release `held` and add `add` to the state, with release semantics
+    fn release(&self, held: usize, add: usize) {
+        loop {
+            let state = self.state.fetch();
+            if cfg!(debug_assertions) {
+                let held_now = if held == RW_READER {
+                    state >= RW_READER
+                } else {
+                    state & held != 0
+                };
+                if ! held_now {
+                    panic!("lock dropped, but not locked!!!");
+                }
+            }
+            if self.state.compare_and_swap(state, state - held + add) == state {
+                break;
+            }
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+
+
+pub struct RwSpinlockReadGuard<'a, T: ?Sized + 'a> {
+    spinlock: &'a RwSpinlock<T>,
+    data: &'a T,
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinlockReadGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { self.data }
+}
+
+impl<'a, T: ?Sized> Drop for RwSpinlockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.spinlock.release(RW_READER, 0);
+        lockdep_release(self.spinlock);
+    }
+}
+
+
+pub struct RwSpinlockUpgradeableGuard<'a, T: ?Sized + 'a> {
+    spinlock: &'a RwSpinlock<T>,
+    data: &'a T,
+}
+
+impl<'a, T: ?Sized> RwSpinlockUpgradeableGuard<'a, T> {
+    /// Wait for the other readers to leave and become the writer
+    #[allow(dead_code)]
+    pub fn upgrade(self) -> RwSpinlockWriteGuard<'a, T> {
+        let spinlock = self.spinlock;
+        core::mem::forget(self);
+        loop {
+            let state = spinlock.state.fetch();
+            if state & !(RW_UPGRADEABLE | RW_WRITER_WAITING) == 0 {
+                if spinlock.state.compare_and_swap(state, RW_WRITER) == state {
+                    break;
+                }
+            } else if state & RW_WRITER_WAITING == 0 {
+                spinlock.state.fetch_or(RW_WRITER_WAITING);
+            }
+            crate::target::pause();
+        }
+        RwSpinlockWriteGuard {
+            spinlock: spinlock,
+            data: unsafe { &mut *spinlock.data.get() }
+        }
+    }
+
+    /// Give up the option to upgrade, and be an ordinary reader
+    #[allow(dead_code)]
+    pub fn downgrade(self) -> RwSpinlockReadGuard<'a, T> {
+        let spinlock = self.spinlock;
+        core::mem::forget(self);
+        spinlock.release(RW_UPGRADEABLE, RW_READER);
+        RwSpinlockReadGuard {
+            spinlock: spinlock,
+            data: unsafe { &*spinlock.data.get() }
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinlockUpgradeableGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { self.data }
+}
+
+impl<'a, T: ?Sized> Drop for RwSpinlockUpgradeableGuard<'a, T> {
+    fn drop(&mut self) {
+        self.spinlock.release(RW_UPGRADEABLE, 0);
+        lockdep_release(self.spinlock);
+    }
+}
+
+
+pub struct RwSpinlockWriteGuard<'a, T: ?Sized + 'a> {
+    spinlock: &'a RwSpinlock<T>,
+    data: &'a mut T,
+}
+
+impl<'a, T: ?Sized> RwSpinlockWriteGuard<'a, T> {
+    /// Become a reader, without letting a writer in between
+    #[allow(dead_code)]
+    pub fn downgrade(self) -> RwSpinlockReadGuard<'a, T> {
+        let spinlock = self.spinlock;
+        core::mem::forget(self);
+        spinlock.release(RW_WRITER, RW_READER);
+        RwSpinlockReadGuard {
+            spinlock: spinlock,
+            data: unsafe { &*spinlock.data.get() }
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinlockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T { &*self.data }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwSpinlockWriteGuard<'a, T> {
+    fn deref_mut<'b>(&'b mut self) -> &'b mut T { &mut *self.data }
+}
+
+impl<'a, T: ?Sized> Drop for RwSpinlockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.spinlock.release(RW_WRITER, 0);
+        lockdep_release(self.spinlock);
+    }
+}
+
+
+// The lock kernel subsystems (the memory allocators) use.  Building with
+// --cfg lock="ticket" in CARGO_BUILD_RUSTFLAGS switches them to the fair lock,
+// to compare fairness and throughput against the default test-and-set lock.
+#[cfg(not(lock = "ticket"))]
+pub type Lock<T> = Spinlock<T>;
+
+#[cfg(lock = "ticket")]
+pub type Lock<T> = TicketSpinlock<T>;
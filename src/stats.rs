@@ -0,0 +1,8 @@
+// Kernel statistics, printed at boot (and later on demand)
+
+pub fn display_kernel_stats() {
+    println!("Kernel Stats:");
+    crate::memory::display_stats();
+    #[cfg(lock_stats)]
+    crate::lock_stats::display();
+}
@@ -0,0 +1,35 @@
+// Walking the frame pointer chain.  This needs frame pointers, which the
+// machines/*.env files turn on (-Cforce-frame-pointers=yes).
+
+use crate::memory::stack;
+
+/// Fill `out` with the return addresses of the calling functions, innermost
+/// first, after skipping `skip` of them.  Returns how many were found.  Stops
+/// early at a frame pointer that isn't within a kernel stack.
+#[inline(never)]
+pub fn backtrace(skip: usize, out: &mut [usize]) -> usize {
+    let mut fp: usize;
+    unsafe { asm!("mv {0}, s0", out(reg) fp) };
+
+    let mut found = 0;
+    let mut depth = 0;
+    // The frame record is the return address at fp - 8, the caller's fp at
+    // fp - 16
+    while found < out.len() && fp % 8 == 0 && fp >= 16
+        && stack::is_stack(fp - 16) && stack::is_stack(fp - 8)
+    {
+        let ra = unsafe { *((fp - 8) as *const usize) };
+        let caller_fp = unsafe { *((fp - 16) as *const usize) };
+        if depth >= skip {
+            out[found] = ra;
+            found += 1;
+        }
+        depth += 1;
+        // Callers' frames are above ours
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    found
+}
@@ -0,0 +1,66 @@
+// Data cache maintenance with Zicbom (cache block management) instructions,
+// for DMA on platforms where devices don't snoop the caches.  boot.S sets
+// menvcfg.CBIE and CBCFE, which supervisor mode needs to use them.
+//
+// On harts without Zicbom these do nothing, so users on non-coherent
+// machines must check available() first (as dma.rs does).
+
+use crate::atomic::{Atomic, AtomicBool, AtomicUSize};
+
+static ZICBOM: AtomicBool = AtomicBool::new(false);
+static BLOCK_SIZE: AtomicUSize = AtomicUSize::new(64);
+
+/// Check the device tree for Zicbom (and its block size) on every hart
+pub fn init() {
+    if crate::target::isa::all_harts_have("zicbom") {
+        if let Some(size) = crate::target::isa::all_harts_property_u32("riscv,cbom-block-size") {
+            BLOCK_SIZE.store(size as usize);
+        }
+        ZICBOM.store(true);
+    }
+}
+
+/// Whether cache maintenance instructions are available
+#[inline(always)]
+pub fn available() -> bool {
+    ZICBOM.fetch()
+}
+
+/// The size of a cache block, which the operations below work in
+#[inline(always)]
+pub fn block_size() -> usize {
+    BLOCK_SIZE.fetch()
+}
+
+// Run `op` (an encoding of cbo.* with rs1 = a0) on each cache block of
+// `size` bytes at virtual address `va`
+macro_rules! for_each_block {
+    ($op:literal, $va:expr, $size:expr) => {
+        if available() {
+            let block = block_size();
+            for addr in ($va & !(block - 1)..$va + $size).step_by(block) {
+                unsafe { asm!(concat!(".word ", $op), in("a0") addr) };
+            }
+        }
+    };
+}
+
+/// Write back dirty cache blocks (keeping them cached), so a device reading
+/// memory sees what the CPU wrote
+#[allow(dead_code)]
+pub fn clean(va: usize, size: usize) {
+    for_each_block!("0x0015200F", va, size); // cbo.clean (a0)
+}
+
+/// Throw away cached copies (without writing them back), so the CPU reads
+/// what a device wrote
+#[allow(dead_code)]
+pub fn invalidate(va: usize, size: usize) {
+    for_each_block!("0x0005200F", va, size); // cbo.inval (a0)
+}
+
+/// Write back and throw away cached copies
+#[allow(dead_code)]
+pub fn flush(va: usize, size: usize) {
+    for_each_block!("0x0025200F", va, size); // cbo.flush (a0)
+}
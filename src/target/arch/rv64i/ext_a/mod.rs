@@ -0,0 +1,3 @@
+
+mod atomic;
+pub use atomic::*;
@@ -0,0 +1,49 @@
+// ISA extensions, as the device tree gives them for each hart
+
+// Whether an ISA string (e.g. "rv64imafdc_zicsr_svpbmt") has extension `ext`
+fn isa_string_has(isa: &str, ext: &str) -> bool {
+    isa.split('_').skip(1).any(|e| e.eq_ignore_ascii_case(ext))
+}
+
+/// Whether every hart with an MMU has extension `ext` (a multi-letter
+/// extension, e.g. "svpbmt"), in either its riscv,isa string or its
+/// riscv,isa-extensions list.  False without a device tree.
+pub fn all_harts_have(ext: &str) -> bool {
+    let cpus = match crate::devicetree::get().and_then(|dt| dt.find("/cpus")) {
+        Some(cpus) => cpus,
+        None => return false,
+    };
+    let mut found = false;
+    for cpu in cpus.children().filter(|c| c.base_name() == "cpu") {
+        if cpu.property("mmu-type").is_none() {
+            continue; // e.g. the S7 monitor core
+        }
+        let has = cpu.property_str("riscv,isa").map(|isa| isa_string_has(isa, ext)).unwrap_or(false)
+            || cpu.property("riscv,isa-extensions").map(|exts| {
+                exts.split(|&b| b == 0).any(|e| e == ext.as_bytes())
+            }).unwrap_or(false);
+        if ! has {
+            return false;
+        }
+        found = true;
+    }
+    found
+}
+
+/// A u32 property that every hart with an MMU has the same value of (e.g.
+/// riscv,cbom-block-size)
+pub fn all_harts_property_u32(name: &str) -> Option<u32> {
+    let cpus = crate::devicetree::get()?.find("/cpus")?;
+    let mut value = None;
+    for cpu in cpus.children().filter(|c| c.base_name() == "cpu") {
+        if cpu.property("mmu-type").is_none() {
+            continue;
+        }
+        let this = cpu.property_u32(name)?;
+        if value.map_or(false, |v| v != this) {
+            return None;
+        }
+        value = Some(this);
+    }
+    value
+}
@@ -0,0 +1,64 @@
+
+// Extension specific code
+#[cfg(target_feature = "a")]
+mod ext_a;
+#[cfg(target_feature = "a")]
+pub use ext_a::*;
+#[cfg(not(target_feature = "a"))]
+compile_error!("rv64i is only currently supported if the Atomic extension is available.");
+
+#[no_mangle]
+pub extern "C" fn abort() -> ! {
+    loop {
+	unsafe {
+	    llvm_asm!("wfi"::::"volatile"); // Wait for interrupt
+	}
+    }
+}
+
+/// Stop this hart until an interrupt is pending (or for no reason at all, as
+/// wfi is allowed to)
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") };
+}
+
+mod ordering;
+pub use ordering::*;
+
+pub mod backtrace;
+pub mod cache;
+pub mod isa;
+pub mod paging;
+pub mod trap;
+pub mod uaccess;
+
+// The hart id (not the dense index, see hart.rs)
+#[inline(always)]
+#[allow(dead_code)]
+pub fn cpu_number() -> u32 {
+    crate::hart::hart_id() as u32
+}
+
+// The thread pointer holds this hart's HartArea (see hart.rs)
+#[inline(always)]
+pub fn hart_area() -> usize {
+    let area: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) area); }
+    area
+}
+
+#[inline(always)]
+pub fn set_hart_area(area: usize) {
+    unsafe { asm!("mv tp, {0}", in(reg) area); }
+    trap::set_hart_area(area);
+}
+
+// The hart's cycle counter (boot.S lets supervisor mode read it)
+#[inline(always)]
+#[allow(dead_code)]
+pub fn cycles() -> u64 {
+    let cycles: u64;
+    unsafe { asm!("rdcycle {0}", out(reg) cycles); }
+    cycles
+}
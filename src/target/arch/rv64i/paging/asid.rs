@@ -0,0 +1,239 @@
+// Address space identifiers.
+//
+// Each address space gets an ASID tagged with a generation.  While its
+// generation is current, switching to it needs no TLB flush at all.  When the
+// ASIDs run out the generation advances: every hart keeps the ASID it is
+// running (so nothing live changes ASID underneath it), every other address
+// space gets a new ASID the next time it is switched to, and every hart
+// flushes its whole TLB before it next switches.
+//
+// On harts with no ASID bits, every switch flushes the TLB instead.
+
+use crate::atomic::{Atomic, AtomicBool, AtomicU64, AtomicUSize};
+use crate::spinlock::Lock;
+use crate::target::{MAX_HARTS, cpu_number};
+use super::{PageTable, sfence_vma_all, sfence_vma_addr, sfence_vma_asid,
+            sfence_vma_addr_asid};
+
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xFFFF;
+const MAX_ASIDS: usize = 1 << 16;
+
+static ASID_BITS: AtomicUSize = AtomicUSize::new(0);
+
+// The current generation, in the bits above the ASID
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// The ASID context id each hart is running (0 while a rollover is pending)
+const ACTIVE_INIT: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: [AtomicU64; MAX_HARTS] = [ACTIVE_INIT; MAX_HARTS];
+
+// Whether each hart must flush its TLB before its next switch
+const FLUSH_PENDING_INIT: AtomicBool = AtomicBool::new(false);
+static FLUSH_PENDING: [AtomicBool; MAX_HARTS] = [FLUSH_PENDING_INIT; MAX_HARTS];
+
+struct AsidAllocator {
+    used: [u64; MAX_ASIDS / 64],   // ASIDs taken in this generation
+    next: usize,                   // where to look for a free ASID
+    reserved: [u64; MAX_HARTS],    // context ids kept over the last rollover
+}
+
+static ALLOCATOR: Lock<AsidAllocator> = Lock::new(AsidAllocator {
+    used: [0; MAX_ASIDS / 64],
+    next: 1,
+    reserved: [0; MAX_HARTS],
+});
+
+/// The ASID of an address space.  Starts without one; one is given the first
+/// time it is switched to.
+pub struct AsidContext {
+    id: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl AsidContext {
+    pub const fn new() -> AsidContext {
+        AsidContext { id: AtomicU64::new(0) }
+    }
+
+    /// The ASID, if it is from the current generation
+    pub fn asid(&self) -> Option<usize> {
+        let id = self.id.fetch();
+        if id != 0 && is_current(id) {
+            Some(asid_of(id))
+        } else {
+            None
+        }
+    }
+}
+
+#[inline(always)]
+fn num_asids() -> usize {
+    1 << ASID_BITS.fetch()
+}
+
+#[inline(always)]
+fn asid_of(id: u64) -> usize {
+    (id as usize) & (num_asids() - 1)
+}
+
+#[inline(always)]
+fn is_current(id: u64) -> bool {
+    (id ^ GENERATION.fetch()) >> ASID_BITS.fetch() == 0
+}
+
+impl AsidAllocator {
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn set_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    // Start a new generation
+    fn rollover(&mut self) {
+        for word in self.used.iter_mut() {
+            *word = 0;
+        }
+
+        // Keep what each hart is running, so it stays valid on that hart
+        for hart in 0..MAX_HARTS {
+            let mut id = ACTIVE[hart].swap_seqcst(0);
+            if id == 0 {
+                // A rollover already happened since this hart last switched
+                id = self.reserved[hart];
+            }
+            self.reserved[hart] = id;
+            if id != 0 {
+                self.set_used(asid_of(id));
+            }
+            FLUSH_PENDING[hart].store_rel(true);
+        }
+
+        GENERATION.fetch_add(num_asids() as u64);
+        self.next = 1;
+    }
+
+    // Give a context from an old generation a new context id
+    fn new_context(&mut self, old: u64) -> u64 {
+        let generation = GENERATION.fetch();
+        let asids = num_asids();
+
+        // If a hart kept this context over a rollover, it keeps its ASID too
+        if old != 0 {
+            let mut kept = false;
+            for reserved in self.reserved.iter_mut() {
+                if *reserved == old {
+                    *reserved = generation | asid_of(old) as u64;
+                    kept = true;
+                }
+            }
+            if kept {
+                return generation | asid_of(old) as u64;
+            }
+        }
+
+        // ASID 0 is the kernel's, and never given out
+        let asid = match (self.next..asids).find(|&a| ! self.is_used(a)) {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                (1..asids).find(|&a| ! self.is_used(a))
+                    .expect("More harts than ASIDs.\n")
+            }
+        };
+        self.set_used(asid);
+        self.next = asid + 1;
+        GENERATION.fetch() | asid as u64
+    }
+}
+
+/// Find how many ASID bits this hart implements, by setting all ones in the
+/// ASID field of satp and reading back which stuck.  MODE and PPN are kept,
+/// as we are running translated.
+pub fn init() {
+    let probed: usize;
+    unsafe {
+        let old: usize;
+        asm!("csrr {0}, satp", out(reg) old);
+        asm!("csrw satp, {1}",
+             "csrr {0}, satp",
+             "csrw satp, {2}",
+             "sfence.vma",
+             out(reg) probed,
+             in(reg) old | (SATP_ASID_MASK << SATP_ASID_SHIFT),
+             in(reg) old);
+    }
+    let implemented = (probed >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+    ASID_BITS.store(implemented.count_ones() as usize);
+}
+
+/// The number of ASID bits this hart implements
+pub fn asid_bits() -> usize {
+    ASID_BITS.fetch()
+}
+
+/// Switch this hart to the address space of `pt`, whose ASID context is
+/// `ctx`.
+pub fn switch_to(pt: &PageTable, ctx: &AsidContext) {
+    if num_asids() <= 2 {
+        // With no ASID bits (or just the kernel's ASID and one other) there
+        // is nothing to gain; tag nothing and flush every time
+        unsafe { write_satp(pt.satp()) };
+        sfence_vma_all();
+        return;
+    }
+
+    let hart = cpu_number() as usize;
+    let mut id = ctx.id.fetch();
+    let old_active = ACTIVE[hart].fetch();
+
+    // The fast path: nothing to do but write satp.  The compare and swap
+    // fails if a rollover happened on another hart since we checked.
+    let fast = old_active != 0
+        && id != 0
+        && is_current(id)
+        && ACTIVE[hart].compare_and_swap(old_active, id) == old_active;
+
+    if ! fast {
+        let mut allocator = ALLOCATOR.lock();
+        id = ctx.id.fetch();
+        if id == 0 || ! is_current(id) {
+            id = allocator.new_context(id);
+            ctx.id.store_rel(id);
+        }
+        if FLUSH_PENDING[hart].swap_seqcst(false) {
+            sfence_vma_all();
+        }
+        ACTIVE[hart].store_rel(id);
+    }
+
+    unsafe { write_satp(pt.satp() | (asid_of(id) << SATP_ASID_SHIFT)) };
+}
+
+#[inline(always)]
+unsafe fn write_satp(satp: usize) {
+    asm!("csrw satp, {0}", in(reg) satp);
+}
+
+/// Flush this hart's TLB entries for the address space of `ctx`.  An ASID
+/// from an old generation needs nothing; it is flushed before reuse.
+#[allow(dead_code)]
+pub fn flush_context(ctx: &AsidContext) {
+    if num_asids() <= 2 {
+        sfence_vma_all();
+    } else if let Some(asid) = ctx.asid() {
+        sfence_vma_asid(asid);
+    }
+}
+
+/// Flush this hart's TLB entry for `va` in the address space of `ctx`
+#[allow(dead_code)]
+pub fn flush_page(ctx: &AsidContext, va: usize) {
+    if num_asids() <= 2 {
+        sfence_vma_addr(va);
+    } else if let Some(asid) = ctx.asid() {
+        sfence_vma_addr_asid(va, asid);
+    }
+}
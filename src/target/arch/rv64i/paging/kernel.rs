@@ -0,0 +1,136 @@
+// The kernel's own page table, which replaces the early page table from
+// boot.S.  Each part of the kernel image is mapped with the permissions of its
+// segment in link.lds, so no kernel page is both writable and executable.
+
+use crate::memory::{layout, stack, DIRECT_MAP_BASE, KERNEL_VIRT_BASE, MMIO_MAP_BASE, PAGE_SIZE,
+                    kernel_virt_to_phys, mmio_phys_to_virt, virt_to_phys, page_round_down,
+                    page_round_up};
+use crate::spinlock::Spinlock;
+use super::{MemoryType, PageTable, PteFlags, PagingError};
+
+pub static KERNEL_PAGE_TABLE: Spinlock<Option<PageTable>> = Spinlock::new(None);
+
+// Map part of the kernel image at its linked address
+fn map_image(pt: &mut PageTable, start: usize, end: usize, flags: PteFlags) {
+    let start = page_round_down(start);
+    let end = page_round_up(end);
+    pt.map_range(start, kernel_virt_to_phys(start), end - start, flags | PteFlags::G)
+        .expect("Could not map the kernel image.\n");
+}
+
+// Add physical memory to the direct map, skipping pages that already are
+fn map_direct(pt: &mut PageTable, pa: usize, size: usize) {
+    let mut page = page_round_down(pa);
+    while page < pa + size {
+        match pt.map(DIRECT_MAP_BASE + page, page, PteFlags::RW | PteFlags::G) {
+            Ok(()) | Err(PagingError::AlreadyMapped { .. })
+                | Err(PagingError::InsideHugePage { .. }) => { },
+            Err(_) => panic!("Could not map physical memory.\n"),
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+// Whether device registers at `pa` fit in the MMIO map (below the kernel image)
+fn in_mmio_map(pa: usize, size: usize) -> bool {
+    pa.checked_add(size).map_or(false, |end| end <= KERNEL_VIRT_BASE - MMIO_MAP_BASE)
+}
+
+// Map device registers into the MMIO map, with the IO memory type
+fn map_mmio_in(pt: &mut PageTable, pa: usize, size: usize) -> Result<(), PagingError> {
+    if ! in_mmio_map(pa, size) {
+        return Err(PagingError::OutsideMmioMap);
+    }
+    let start = page_round_down(pa);
+    let end = page_round_up(pa + size);
+    pt.map_range_typed(mmio_phys_to_virt(start), start, end - start, PteFlags::RW | PteFlags::G,
+                       MemoryType::Io)
+}
+
+/// Build the kernel page table (in the paging mode chosen by mode::init())
+/// and switch this hart over to it
+pub fn init() {
+    let mut pt = PageTable::new().expect("No memory for the kernel page table.\n");
+
+    map_image(&mut pt, layout::text_start(), layout::text_end(), PteFlags::RX);
+    map_image(&mut pt, layout::rodata_start(), layout::rodata_end(), PteFlags::R);
+    map_image(&mut pt, layout::data_start(), layout::stacks_start(), PteFlags::RW);
+
+    // The boot stacks, leaving their guard pages unmapped
+    for hart in 0..stack::boot_stack_count() {
+        let (bottom, top) = stack::boot_stack(hart);
+        map_image(&mut pt, bottom, top, PteFlags::RW);
+    }
+
+    // The direct map of physical memory (never executable), in huge pages
+    let mem_start = layout::memory_start();
+    let mem_end = layout::memory_end();
+    if mem_end > MMIO_MAP_BASE - DIRECT_MAP_BASE {
+        panic!("Physical memory runs past the direct map.\n");
+    }
+    pt.map_range(DIRECT_MAP_BASE + mem_start, mem_start, mem_end - mem_start,
+                 PteFlags::RW | PteFlags::G)
+        .expect("Could not map physical memory.\n");
+
+    // The device tree may be outside of the memory we were given
+    if let Some(dt) = crate::devicetree::get() {
+        map_direct(&mut pt, virt_to_phys(dt.address()), dt.size());
+    }
+
+    // Devices are reached through the MMIO map
+    for &(base, size) in crate::target::MMIO_REGIONS {
+        map_mmio_in(&mut pt, base, size).expect("Could not map devices.\n");
+    }
+
+    // Address spaces copy our kernel half root entries once
+    // (PageTable::new_sharing()), and every later kernel mapping (the direct
+    // map, the MMIO map, stacks) is in the kernel half, so give each of them
+    // its table now
+    let kernel_half = !0 << (pt.mode().va_bits() - 1);
+    pt.populate_root(kernel_half, !0).expect("No memory for the kernel page table.\n");
+
+    unsafe { pt.activate() };
+    *KERNEL_PAGE_TABLE.lock() = Some(pt);
+}
+
+/// Add physical memory beyond that given in link.lds (e.g. the L2 LIM) to the
+/// direct map
+pub fn add_to_direct_map(pa: usize, size: usize) {
+    let mut kpt = KERNEL_PAGE_TABLE.lock();
+    let pt = kpt.as_mut().expect("The kernel page table is not set up.\n");
+    map_direct(pt, pa, size);
+    super::sfence_vma_all();
+}
+
+/// Map device registers into the MMIO map (with the IO memory type) for a
+/// driver whose device is not in the machine's MMIO_REGIONS, returning their
+/// virtual address
+pub fn map_mmio(pa: usize, size: usize) -> Result<usize, PagingError> {
+    if ! in_mmio_map(pa, size) {
+        return Err(PagingError::OutsideMmioMap);
+    }
+    let mut kpt = KERNEL_PAGE_TABLE.lock();
+    let pt = kpt.as_mut().expect("The kernel page table is not set up.\n");
+    let mut page = page_round_down(pa);
+    while page < pa + size {
+        match pt.map_large_typed(mmio_phys_to_virt(page), page, 0, PteFlags::RW | PteFlags::G,
+                                 MemoryType::Io) {
+            Ok(()) | Err(PagingError::AlreadyMapped { .. })
+                | Err(PagingError::InsideHugePage { .. }) => { },
+            Err(e) => return Err(e),
+        }
+        page += PAGE_SIZE;
+    }
+    super::sfence_vma_all();
+    Ok(mmio_phys_to_virt(pa))
+}
+
+/// Change the memory type of physical memory in the direct map (e.g. to Nc
+/// for a DMA buffer, which must then only be reached through the direct map)
+pub fn set_direct_map_type(pa: usize, size: usize, mt: MemoryType) -> Result<(), PagingError> {
+    let start = page_round_down(pa);
+    let end = page_round_up(pa + size);
+    let mut kpt = KERNEL_PAGE_TABLE.lock();
+    let pt = kpt.as_mut().expect("The kernel page table is not set up.\n");
+    pt.retype_range(DIRECT_MAP_BASE + start, end - start, mt)
+}
@@ -0,0 +1,519 @@
+// Sv39, Sv48 and Sv57 page tables.  See the RISC-V privileged specification,
+// sections 4.4 to 4.6
+
+mod pte;
+pub use pte::{MemoryType, Pte, PteFlags};
+
+pub mod asid;
+pub mod kernel;
+pub mod mode;
+pub use mode::PagingMode;
+pub mod pbmt;
+
+use crate::memory::{frame, phys_to_virt, PAGE_SIZE};
+
+/// Entries per page table
+pub const ENTRIES: usize = 512;
+
+/// The highest level at which we map huge pages (gigapages, level 2).  Sv48
+/// and Sv57 allow larger pages still, but we never have that much to map.
+pub const MAX_LEAF_LEVEL: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingError {
+    /// The virtual address is already mapped (to the physical address given)
+    AlreadyMapped { va: usize, pa: usize },
+    /// The virtual address is inside a larger page
+    InsideHugePage { va: usize },
+    /// The virtual address is not mapped
+    NotMapped { va: usize },
+    /// An address was not aligned to the page size
+    Misaligned,
+    /// Pages of the size asked for can not be mapped
+    BadPageSize,
+    /// Something is already mapped within the range of the huge page at `va`
+    PartlyMapped { va: usize },
+    /// The flags do not give any of R, W or X
+    NoPermissions,
+    /// The flags give W without R, a reserved encoding
+    WriteWithoutRead,
+    /// The virtual address is not sign-extended from the top bit the paging
+    /// mode translates
+    NonCanonical,
+    /// The frame allocator could not supply a page table
+    OutOfFrames,
+    /// Device registers are beyond what the MMIO map covers
+    OutsideMmioMap,
+}
+
+#[repr(C, align(4096))]
+struct Table {
+    entries: [Pte; ENTRIES]
+}
+
+#[inline(always)]
+unsafe fn table_at(pa: usize) -> *mut Table {
+    phys_to_virt(pa) as *mut Table
+}
+
+/// The index into the table at `level` for virtual address `va`
+#[inline(always)]
+fn vpn(va: usize, level: usize) -> usize {
+    (va >> (12 + 9 * level)) & (ENTRIES - 1)
+}
+
+/// The size of memory mapped by a leaf at `level`
+#[inline(always)]
+pub const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+#[inline(always)]
+pub fn is_canonical(va: usize, mode: PagingMode) -> bool {
+    let top = (va as isize) >> (mode.va_bits() - 1);
+    top == 0 || top == -1
+}
+
+/// Flush the whole local TLB
+#[allow(dead_code)]
+#[inline(always)]
+pub fn sfence_vma_all() {
+    unsafe { asm!("sfence.vma"); }
+}
+
+/// Flush local TLB entries for one virtual address (in every address space)
+#[allow(dead_code)]
+#[inline(always)]
+pub fn sfence_vma_addr(va: usize) {
+    unsafe { asm!("sfence.vma {0}, zero", in(reg) va); }
+}
+
+/// Flush local TLB entries for one address space, other than global mappings
+#[allow(dead_code)]
+#[inline(always)]
+pub fn sfence_vma_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {0}", in(reg) asid); }
+}
+
+/// Flush local TLB entries for one virtual address in one address space
+#[allow(dead_code)]
+#[inline(always)]
+pub fn sfence_vma_addr_asid(va: usize, asid: usize) {
+    unsafe { asm!("sfence.vma {0}, {1}", in(reg) va, in(reg) asid); }
+}
+
+/// A page table tree, rooted at a physical frame, with as many levels as the
+/// paging mode in use when it was made.
+///
+/// Intermediate tables come from the frame allocator.  Ranges are mapped with
+/// 2 MiB and 1 GiB pages where alignment and size allow, and a huge page is
+/// split when only part of it is unmapped or protected.  Mappings are created
+/// with A (and for writable pages D) already set, so harts that raise page
+/// faults instead of updating A/D in hardware won't fault on them; callers
+/// that track access use `take_accessed()` and `take_dirty()`.
+pub struct PageTable {
+    root: usize,
+    mode: PagingMode,
+    shared: [u64; ENTRIES / 64],    // root entries that belong to another table
+}
+
+#[allow(dead_code)]
+impl PageTable {
+    /// Create an empty page table
+    pub fn new() -> Result<PageTable, PagingError> {
+        let root = frame::alloc_frame_zeroed().ok_or(PagingError::OutOfFrames)?;
+        Ok(PageTable { root: root, mode: mode::current(), shared: [0; ENTRIES / 64] })
+    }
+
+    /// Create a page table for a new address space, sharing every mapping
+    /// `kernel` has in the kernel half (by sharing its root entries).  Root
+    /// entries `kernel` gains later are not seen, which is why kernel::init()
+    /// gives every one in the kernel half a table up front.  The lower half
+    /// is left to the address space.
+    pub fn new_sharing(kernel: &PageTable) -> Result<PageTable, PagingError> {
+        let mut pt = PageTable::new()?;
+        let from = unsafe { &*table_at(kernel.root) };
+        let to = unsafe { &mut *table_at(pt.root) };
+        for (i, pte) in from.entries.iter().enumerate().skip(ENTRIES / 2) {
+            if pte.is_valid() {
+                to.entries[i] = *pte;
+                pt.shared[i / 64] |= 1 << (i % 64);
+            }
+        }
+        Ok(pt)
+    }
+
+    /// Whether `va` is in the part of the address space shared with another
+    /// page table (see `new_sharing()`), and so must not be changed here
+    pub fn is_shared(&self, va: usize) -> bool {
+        let i = vpn(va, self.mode.levels() - 1);
+        self.shared[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Give every unused root entry covering `first..=last` an empty table,
+    /// so that later mappings in that range never change the root table
+    pub fn populate_root(&mut self, first: usize, last: usize) -> Result<(), PagingError> {
+        let top = self.mode.levels() - 1;
+        let root = unsafe { &mut *table_at(self.root) };
+        for pte in &mut root.entries[vpn(first, top)..=vpn(last, top)] {
+            if ! pte.is_valid() {
+                let pa = frame::alloc_frame_zeroed().ok_or(PagingError::OutOfFrames)?;
+                *pte = Pte::table(pa);
+            }
+        }
+        Ok(())
+    }
+
+    /// Physical address of the root table
+    #[inline(always)]
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    #[inline(always)]
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    /// The value to write into `satp` to use this page table (with ASID 0)
+    #[inline(always)]
+    pub fn satp(&self) -> usize {
+        (self.mode.satp_mode() << 60) | (self.root >> 12)
+    }
+
+    /// Switch this hart over to this page table
+    pub unsafe fn activate(&self) {
+        asm!("csrw satp, {0}",
+             "sfence.vma",
+             in(reg) self.satp());
+    }
+
+    // Walk down to the entry at `level` for `va`, making tables on the way if
+    // `create` is set.
+    fn walk(&mut self, va: usize, level: usize, create: bool) -> Result<*mut Pte, PagingError> {
+        let mut table = unsafe { table_at(self.root) };
+        for l in (level + 1..self.mode.levels()).rev() {
+            let pte = unsafe { &mut (*table).entries[vpn(va, l)] };
+            if ! pte.is_valid() {
+                if ! create {
+                    return Err(PagingError::NotMapped { va: va });
+                }
+                let pa = frame::alloc_frame_zeroed().ok_or(PagingError::OutOfFrames)?;
+                *pte = Pte::table(pa);
+            } else if pte.is_leaf() {
+                return Err(PagingError::InsideHugePage { va: va });
+            }
+            table = unsafe { table_at(pte.addr()) };
+        }
+        Ok(unsafe { &mut (*table).entries[vpn(va, level)] as *mut Pte })
+    }
+
+    // Find the valid leaf entry mapping `va`, at whatever level it is
+    fn leaf(&self, va: usize) -> Result<(*mut Pte, usize), PagingError> {
+        if ! is_canonical(va, self.mode) {
+            return Err(PagingError::NonCanonical);
+        }
+        let mut table = unsafe { table_at(self.root) };
+        for level in (0..self.mode.levels()).rev() {
+            let pte = unsafe { &mut (*table).entries[vpn(va, level)] };
+            if ! pte.is_valid() {
+                break;
+            }
+            if pte.is_leaf() {
+                return Ok((pte as *mut Pte, level));
+            }
+            table = unsafe { table_at(pte.addr()) };
+        }
+        Err(PagingError::NotMapped { va: va })
+    }
+
+    // Whether nothing at all is mapped in the page at `level` containing `va`
+    fn is_unmapped(&self, va: usize, level: usize) -> bool {
+        let mut table = unsafe { table_at(self.root) };
+        for l in (level..self.mode.levels()).rev() {
+            let pte = unsafe { (*table).entries[vpn(va, l)] };
+            if ! pte.is_valid() {
+                return true;
+            }
+            if pte.is_leaf() || l == level {
+                return false;
+            }
+            table = unsafe { table_at(pte.addr()) };
+        }
+        true
+    }
+
+    // The largest page size (as a level) we can map at `va` to `pa` within
+    // `size` bytes
+    fn best_level(&self, va: usize, pa: usize, size: usize) -> usize {
+        let top = MAX_LEAF_LEVEL.min(self.mode.levels() - 1);
+        for level in (1..=top).rev() {
+            let page = level_size(level);
+            if va % page == 0 && pa % page == 0 && size >= page && self.is_unmapped(va, level) {
+                return level;
+            }
+        }
+        0
+    }
+
+    // Replace the huge page leaf `pte` at `level` with a table of leaves one
+    // level down, mapping the same memory with the same flags
+    fn split(&mut self, pte: *mut Pte, level: usize) -> Result<(), PagingError> {
+        let table_pa = frame::alloc_frame_zeroed().ok_or(PagingError::OutOfFrames)?;
+        let table = unsafe { &mut *table_at(table_pa) };
+        let old = unsafe { *pte };
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            *entry = Pte::new(old.addr() + i * level_size(level - 1), old.flags())
+                .with_memory_type(old.memory_type());
+        }
+        // The translations are unchanged, so no TLB flush is needed yet
+        unsafe { *pte = Pte::table(table_pa) };
+        Ok(())
+    }
+
+    /// Map the page at `va` to the frame at `pa`
+    pub fn map(&mut self, va: usize, pa: usize, flags: PteFlags) -> Result<(), PagingError> {
+        self.map_large(va, pa, 0, flags)
+    }
+
+    /// Map a page of `level_size(level)` bytes (level 1 is a 2 MiB megapage,
+    /// level 2 a 1 GiB gigapage) at `va` to physical memory at `pa`
+    pub fn map_large(&mut self, va: usize, pa: usize, level: usize, flags: PteFlags)
+                     -> Result<(), PagingError>
+    {
+        self.map_large_typed(va, pa, level, flags, MemoryType::Pma)
+    }
+
+    /// As `map_large()`, with memory type `mt` (where Svpbmt is available)
+    pub fn map_large_typed(&mut self, va: usize, pa: usize, level: usize, flags: PteFlags,
+                           mt: MemoryType) -> Result<(), PagingError>
+    {
+        if level > MAX_LEAF_LEVEL || level >= self.mode.levels() {
+            return Err(PagingError::BadPageSize);
+        }
+        if va % level_size(level) != 0 || pa % level_size(level) != 0 {
+            return Err(PagingError::Misaligned);
+        }
+        if ! is_canonical(va, self.mode) {
+            return Err(PagingError::NonCanonical);
+        }
+        if ! flags.is_leaf() {
+            return Err(PagingError::NoPermissions);
+        }
+        if flags.is_write_only() {
+            return Err(PagingError::WriteWithoutRead);
+        }
+        let pte = unsafe { &mut *self.walk(va, level, true)? };
+        if pte.is_leaf() {
+            return Err(PagingError::AlreadyMapped { va: va, pa: pte.addr() });
+        }
+        if pte.is_valid() {
+            return Err(PagingError::PartlyMapped { va: va });
+        }
+        *pte = Pte::new(pa, with_ad(flags)).with_memory_type(pbmt::effective(mt));
+        Ok(())
+    }
+
+    /// Map `size` bytes at `va` to physical memory at `pa`, using the largest
+    /// pages that alignment and size allow.  If any page is already mapped,
+    /// the pages mapped by this call are unmapped again.
+    pub fn map_range(&mut self, va: usize, pa: usize, size: usize, flags: PteFlags)
+                     -> Result<(), PagingError>
+    {
+        self.map_range_typed(va, pa, size, flags, MemoryType::Pma)
+    }
+
+    /// As `map_range()`, with memory type `mt` (where Svpbmt is available)
+    pub fn map_range_typed(&mut self, va: usize, pa: usize, size: usize, flags: PteFlags,
+                           mt: MemoryType) -> Result<(), PagingError>
+    {
+        let mut offset = 0;
+        while offset < size {
+            let level = self.best_level(va + offset, pa + offset, size - offset);
+            if let Err(e) = self.map_large_typed(va + offset, pa + offset, level, flags, mt) {
+                let _ = self.unmap_range(va, offset);
+                return Err(e);
+            }
+            offset += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping of the page at `va`, returning the physical address
+    /// it was mapped to.  A huge page around it is split.
+    pub fn unmap(&mut self, va: usize) -> Result<usize, PagingError> {
+        let pa = self.translate(va & !(PAGE_SIZE - 1))
+            .ok_or(PagingError::NotMapped { va: va })?;
+        self.unmap_range(va & !(PAGE_SIZE - 1), PAGE_SIZE)?;
+        Ok(pa)
+    }
+
+    /// Remove the mappings of `size` bytes at `va`, skipping what is not
+    /// mapped.  Huge pages only partly in the range are split.
+    pub fn unmap_range(&mut self, va: usize, size: usize) -> Result<(), PagingError> {
+        if va % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(PagingError::Misaligned);
+        }
+        let mut offset = 0;
+        while offset < size {
+            let (pte, level) = match self.leaf(va + offset) {
+                Ok(found) => found,
+                Err(PagingError::NotMapped { .. }) => {
+                    offset += PAGE_SIZE;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            let page = level_size(level);
+            if (va + offset) % page == 0 && size - offset >= page {
+                unsafe { *pte = Pte::INVALID };
+                sfence_vma_addr(va + offset);
+                offset += page;
+            } else {
+                self.split(pte, level)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the permissions of the page at `va`.  A huge page around it is
+    /// split.
+    pub fn protect(&mut self, va: usize, flags: PteFlags) -> Result<(), PagingError> {
+        self.protect_range(va & !(PAGE_SIZE - 1), PAGE_SIZE, flags)
+    }
+
+    /// Change the permissions of `size` bytes at `va`, all of which must be
+    /// mapped.  Huge pages only partly in the range are split.
+    pub fn protect_range(&mut self, va: usize, size: usize, flags: PteFlags)
+                         -> Result<(), PagingError>
+    {
+        if ! flags.is_leaf() {
+            return Err(PagingError::NoPermissions);
+        }
+        if flags.is_write_only() {
+            return Err(PagingError::WriteWithoutRead);
+        }
+        if va % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(PagingError::Misaligned);
+        }
+        let mut offset = 0;
+        while offset < size {
+            let (pte, level) = self.leaf(va + offset)?;
+            let page = level_size(level);
+            if (va + offset) % page == 0 && size - offset >= page {
+                unsafe { (*pte).set_flags(with_ad(flags) | PteFlags::V) };
+                sfence_vma_addr(va + offset);
+                offset += page;
+            } else {
+                self.split(pte, level)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the memory type of `size` bytes at `va`, all of which must be
+    /// mapped.  Huge pages only partly in the range are split.  Does nothing
+    /// where Svpbmt is not available.
+    pub fn retype_range(&mut self, va: usize, size: usize, mt: MemoryType)
+                        -> Result<(), PagingError>
+    {
+        if ! pbmt::available() {
+            return Ok(());
+        }
+        if va % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(PagingError::Misaligned);
+        }
+        let mut offset = 0;
+        while offset < size {
+            let (pte, level) = self.leaf(va + offset)?;
+            let page = level_size(level);
+            if (va + offset) % page == 0 && size - offset >= page {
+                unsafe { *pte = (*pte).with_memory_type(mt) };
+                sfence_vma_addr(va + offset);
+                offset += page;
+            } else {
+                self.split(pte, level)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate a virtual address into a physical address
+    pub fn translate(&self, va: usize) -> Option<usize> {
+        let (pte, level) = self.leaf(va).ok()?;
+        let offset = va & (level_size(level) - 1);
+        Some(unsafe { (*pte).addr() } + offset)
+    }
+
+    /// Get the leaf entry mapping `va` and the size of the page it maps
+    pub fn lookup(&self, va: usize) -> Option<(Pte, usize)> {
+        let (pte, level) = self.leaf(va).ok()?;
+        Some((unsafe { *pte }, level_size(level)))
+    }
+
+    /// Get whether the page at `va` has been accessed, and clear A so that
+    /// the next access sets it again
+    pub fn take_accessed(&mut self, va: usize) -> Result<bool, PagingError> {
+        self.take_flag(va, PteFlags::A)
+    }
+
+    /// Get whether the page at `va` has been written, and clear D so that
+    /// the next write sets it again
+    pub fn take_dirty(&mut self, va: usize) -> Result<bool, PagingError> {
+        self.take_flag(va, PteFlags::D)
+    }
+
+    fn take_flag(&mut self, va: usize, flag: PteFlags) -> Result<bool, PagingError> {
+        let (pte, _) = self.leaf(va)?;
+        let flags = unsafe { (*pte).flags() };
+        if flags.contains(flag) {
+            unsafe { (*pte).set_flags(flags & !flag) };
+            sfence_vma_addr(va);
+        }
+        Ok(flags.contains(flag))
+    }
+
+    // Free the tables below (not including) the table at `pa`
+    fn free_tables(pa: usize, level: usize) {
+        Self::free_tables_except(pa, level, &[0; ENTRIES / 64]);
+    }
+
+    fn free_tables_except(pa: usize, level: usize, except: &[u64; ENTRIES / 64]) {
+        if level == 0 { return; }
+        let table = unsafe { &*table_at(pa) };
+        for (i, pte) in table.entries.iter().enumerate() {
+            if except[i / 64] & (1 << (i % 64)) != 0 {
+                continue;
+            }
+            if pte.is_valid() && ! pte.is_leaf() {
+                Self::free_tables(pte.addr(), level - 1);
+                frame::free_frame(pte.addr());
+            }
+        }
+    }
+}
+
+impl Drop for PageTable {
+    /// Frees the tables (other than shared ones), but not the frames they
+    /// mapped
+    fn drop(&mut self) {
+        Self::free_tables_except(self.root, self.mode.levels() - 1, &self.shared);
+        frame::free_frame(self.root);
+    }
+}
+
+// Set A, and D if writable, for a new leaf entry
+#[inline(always)]
+fn with_ad(flags: PteFlags) -> PteFlags {
+    let mut flags = flags | PteFlags::A;
+    if flags.contains(PteFlags::W) {
+        flags |= PteFlags::D;
+    }
+    flags
+}
+
+pub fn display_information() {
+    println!("Paging: {} (deepest supported: {}), {} ASID bits, Svpbmt {}",
+             mode::current().name(), mode::supported().name(), asid::asid_bits(),
+             if pbmt::available() { "available" } else { "not available" });
+}
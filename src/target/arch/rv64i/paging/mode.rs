@@ -0,0 +1,103 @@
+// Selection of the paging mode (Sv39, Sv48 or Sv57) at boot.  boot.S finds the
+// deepest mode the hart supports by trying each in satp while still in machine
+// mode; the `paging=sv39|sv48|sv57` command line option can ask for a shallower
+// one.
+
+use crate::atomic::{Atomic, AtomicUSize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+#[allow(dead_code)]
+impl PagingMode {
+    /// Number of levels of page table
+    #[inline(always)]
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Significant virtual address bits
+    #[inline(always)]
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    /// The MODE field of satp
+    #[inline(always)]
+    pub const fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+
+    pub fn from_satp_mode(mode: usize) -> Option<PagingMode> {
+        match mode {
+            8 => Some(PagingMode::Sv39),
+            9 => Some(PagingMode::Sv48),
+            10 => Some(PagingMode::Sv57),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PagingMode> {
+        match name {
+            "sv39" | "Sv39" => Some(PagingMode::Sv39),
+            "sv48" | "Sv48" => Some(PagingMode::Sv48),
+            "sv57" | "Sv57" => Some(PagingMode::Sv57),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PagingMode::Sv39 => "Sv39",
+            PagingMode::Sv48 => "Sv48",
+            PagingMode::Sv57 => "Sv57",
+        }
+    }
+}
+
+extern "C" {
+    // The satp MODE found by boot.S
+    static _paging_mode_max: usize;
+}
+
+// The mode page tables are built for, as a satp MODE
+static MODE: AtomicUSize = AtomicUSize::new(8);
+
+/// The deepest paging mode this hart supports
+pub fn supported() -> PagingMode {
+    // boot.S could not have got us here without Sv39
+    PagingMode::from_satp_mode(unsafe { _paging_mode_max }).unwrap_or(PagingMode::Sv39)
+}
+
+/// The paging mode in use
+#[inline(always)]
+pub fn current() -> PagingMode {
+    PagingMode::from_satp_mode(MODE.fetch()).unwrap_or(PagingMode::Sv39)
+}
+
+/// Choose the paging mode.  This must happen before any page table is made.
+pub fn init() -> PagingMode {
+    let supported = supported();
+    let mut mode = supported;
+    if let Some(name) = crate::cmdline::get("paging") {
+        match PagingMode::from_name(name) {
+            Some(m) if m.levels() <= supported.levels() => mode = m,
+            Some(m) => println!("paging={} is not supported, using {}", m.name(), supported.name()),
+            None => println!("paging={} is not a paging mode, using {}", name, supported.name()),
+        }
+    }
+    MODE.store(mode.satp_mode());
+    mode
+}
@@ -0,0 +1,26 @@
+// Svpbmt (page-based memory types).  Where every hart has it, mappings can be
+// given the IO or NC memory type (see MemoryType); elsewhere the memory type
+// is always left to the platform's PMAs.  boot.S sets menvcfg.PBMTE, which
+// supervisor mode needs to use it.
+
+use crate::atomic::{Atomic, AtomicBool};
+use super::MemoryType;
+
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Check the device tree for Svpbmt on every hart with an MMU
+pub fn init() {
+    SVPBMT.store(crate::target::isa::all_harts_have("svpbmt"));
+}
+
+/// Whether mappings may have memory types other than Pma
+#[inline(always)]
+pub fn available() -> bool {
+    SVPBMT.fetch()
+}
+
+/// `mt`, or Pma if memory types are not available
+#[inline(always)]
+pub fn effective(mt: MemoryType) -> MemoryType {
+    if available() { mt } else { MemoryType::Pma }
+}
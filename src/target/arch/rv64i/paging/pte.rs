@@ -0,0 +1,176 @@
+use core::ops::{BitOr, BitOrAssign, BitAnd, Not};
+
+/// Flag bits of a page table entry (the low 10 bits, see the RISC-V
+/// privileged specification, section 4.3.1)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PteFlags(u64);
+
+#[allow(dead_code)]
+impl PteFlags {
+    pub const NONE: PteFlags = PteFlags(0);
+    pub const V: PteFlags = PteFlags(1 << 0);  // Valid
+    pub const R: PteFlags = PteFlags(1 << 1);  // Readable
+    pub const W: PteFlags = PteFlags(1 << 2);  // Writable
+    pub const X: PteFlags = PteFlags(1 << 3);  // Executable
+    pub const U: PteFlags = PteFlags(1 << 4);  // User accessible
+    pub const G: PteFlags = PteFlags(1 << 5);  // Global (in all address spaces)
+    pub const A: PteFlags = PteFlags(1 << 6);  // Accessed
+    pub const D: PteFlags = PteFlags(1 << 7);  // Dirty
+
+    // Bits 8 and 9 are left to software
+    pub const COW: PteFlags = PteFlags(1 << 8);  // Copy on write (shared, read only)
+
+    // Common permission sets
+    pub const RX: PteFlags = PteFlags(Self::R.0 | Self::X.0);
+    pub const RW: PteFlags = PteFlags(Self::R.0 | Self::W.0);
+
+    /// All of the permission bits
+    const RWX: PteFlags = PteFlags(Self::R.0 | Self::W.0 | Self::X.0);
+
+    #[inline(always)]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    #[inline(always)]
+    pub const fn from_bits(bits: u64) -> PteFlags {
+        PteFlags(bits & 0x3FF)
+    }
+
+    #[inline(always)]
+    pub const fn contains(self, other: PteFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[inline(always)]
+    pub const fn intersects(self, other: PteFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether these flags describe a leaf (any of R, W or X set) rather than
+    /// a pointer to the next level table
+    #[inline(always)]
+    pub const fn is_leaf(self) -> bool {
+        self.intersects(Self::RWX)
+    }
+
+    /// Whether these flags give W without R, which is a reserved encoding
+    /// (the hardware raises a page fault on it)
+    #[inline(always)]
+    pub const fn is_write_only(self) -> bool {
+        self.contains(Self::W) && ! self.contains(Self::R)
+    }
+}
+
+impl BitOr for PteFlags {
+    type Output = PteFlags;
+    fn bitor(self, rhs: PteFlags) -> PteFlags { PteFlags(self.0 | rhs.0) }
+}
+
+impl BitOrAssign for PteFlags {
+    fn bitor_assign(&mut self, rhs: PteFlags) { self.0 |= rhs.0 }
+}
+
+impl BitAnd for PteFlags {
+    type Output = PteFlags;
+    fn bitand(self, rhs: PteFlags) -> PteFlags { PteFlags(self.0 & rhs.0) }
+}
+
+impl Not for PteFlags {
+    type Output = PteFlags;
+    fn not(self) -> PteFlags { PteFlags(!self.0 & 0x3FF) }
+}
+
+/// Svpbmt page-based memory types (bits 61 and 62 of a leaf entry), which
+/// override the memory attributes the platform gives the physical address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryType {
+    /// Whatever the platform's PMAs say (normally cacheable memory)
+    Pma = 0,
+    /// Non-cacheable, idempotent, weakly ordered (for DMA buffers)
+    Nc = 1,
+    /// Non-cacheable, non-idempotent, strongly ordered (for device registers)
+    Io = 2,
+}
+
+/// A page table entry
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Pte(u64);
+
+#[allow(dead_code)]
+impl Pte {
+    pub const INVALID: Pte = Pte(0);
+
+    const PPN_SHIFT: usize = 10;
+    const PPN_MASK: u64 = (1 << 44) - 1;
+    const PBMT_SHIFT: usize = 61;
+    const PBMT_MASK: u64 = 3;
+
+    #[inline(always)]
+    pub const fn new(pa: usize, flags: PteFlags) -> Pte {
+        Pte((((pa as u64) >> 12) & Self::PPN_MASK) << Self::PPN_SHIFT
+            | flags.bits()
+            | PteFlags::V.bits())
+    }
+
+    /// An entry pointing to the next level table at `pa`
+    #[inline(always)]
+    pub const fn table(pa: usize) -> Pte {
+        Pte::new(pa, PteFlags::NONE)
+    }
+
+    #[inline(always)]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    #[inline(always)]
+    pub const fn is_valid(self) -> bool {
+        self.0 & PteFlags::V.bits() != 0
+    }
+
+    #[inline(always)]
+    pub const fn is_leaf(self) -> bool {
+        self.is_valid() && self.flags().is_leaf()
+    }
+
+    #[inline(always)]
+    pub const fn flags(self) -> PteFlags {
+        PteFlags::from_bits(self.0)
+    }
+
+    #[inline(always)]
+    pub fn set_flags(&mut self, flags: PteFlags) {
+        self.0 = (self.0 & !0x3FF) | flags.bits();
+    }
+
+    /// This entry with memory type `mt` (which must be Pma unless the harts
+    /// have Svpbmt)
+    #[inline(always)]
+    pub const fn with_memory_type(self, mt: MemoryType) -> Pte {
+        Pte((self.0 & !(Self::PBMT_MASK << Self::PBMT_SHIFT))
+            | (mt as u64) << Self::PBMT_SHIFT)
+    }
+
+    #[inline(always)]
+    pub fn memory_type(self) -> MemoryType {
+        match (self.0 >> Self::PBMT_SHIFT) & Self::PBMT_MASK {
+            1 => MemoryType::Nc,
+            2 => MemoryType::Io,
+            _ => MemoryType::Pma,
+        }
+    }
+
+    /// The physical address this entry points to
+    #[inline(always)]
+    pub const fn addr(self) -> usize {
+        (((self.0 >> Self::PPN_SHIFT) & Self::PPN_MASK) << 12) as usize
+    }
+}
+
+impl core::fmt::Debug for Pte {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Pte({:#x}, {:#05x})", self.addr(), self.flags().bits())
+    }
+}
@@ -0,0 +1,187 @@
+// Supervisor mode traps.  The entry and exit code is in trap.S.
+//
+// Each hart has a TrapScratch, which sscratch points to, giving trap.S the
+// limit of the stack in use and an emergency stack to switch to if the trap
+// was caused by running off the bottom of that stack.
+
+use crate::atomic::{Atomic, AtomicUSize};
+use crate::memory::stack;
+use crate::memory::vm::{self, Access};
+use crate::target::{MAX_HARTS, cpu_number};
+use super::uaccess;
+
+global_asm!(include_str!("trap.S"));
+
+const EMERGENCY_STACK_SIZE: usize = 16384;
+
+/// The registers of the interrupted code, as saved by trap.S
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],      // x0 (unused) to x31
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+// Offsets are known to trap.S (t0 is kept in sscratch itself)
+#[allow(dead_code)]
+#[repr(C)]
+struct TrapScratch {
+    t1: usize,
+    t2: usize,
+    stack_limit: usize,         // lowest usable address of the current stack
+    emergency_sp: usize,        // 0 once used
+    overflow_sp: usize,         // sp when an overflow was caught
+    hart_area: usize,           // what tp should be (see hart.rs)
+}
+
+const TRAP_SCRATCH_INIT: TrapScratch = TrapScratch {
+    t1: 0, t2: 0, stack_limit: 0, emergency_sp: 0, overflow_sp: 0, hart_area: 0,
+};
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+const EMERGENCY_STACK_INIT: EmergencyStack = EmergencyStack([0; EMERGENCY_STACK_SIZE]);
+
+// Each hart only touches its own entries
+static mut TRAP_SCRATCH: [TrapScratch; MAX_HARTS] = [TRAP_SCRATCH_INIT; MAX_HARTS];
+static mut EMERGENCY_STACKS: [EmergencyStack; MAX_HARTS] = [EMERGENCY_STACK_INIT; MAX_HARTS];
+// How deeply each hart is nested in interrupt handlers
+static INTERRUPT_DEPTH: [AtomicUSize; MAX_HARTS] = [INTERRUPT_DEPTH_INIT; MAX_HARTS];
+const INTERRUPT_DEPTH_INIT: AtomicUSize = AtomicUSize::new(0);
+
+extern "C" {
+    fn trap_vector();
+}
+
+const CAUSE_INTERRUPT: usize = 1 << 63;
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Take over traps on this hart from boot.S's early trap vector.  It must be
+/// running on its boot stack.
+pub fn init() {
+    let hart = cpu_number() as usize;
+    unsafe {
+        let emergency = &EMERGENCY_STACKS[hart] as *const EmergencyStack as usize;
+        TRAP_SCRATCH[hart].emergency_sp = emergency + EMERGENCY_STACK_SIZE;
+        TRAP_SCRATCH[hart].stack_limit = stack::boot_stack(hart).0;
+        asm!("csrw sscratch, {0}",
+             "csrw stvec, {1}",
+             in(reg) &TRAP_SCRATCH[hart] as *const TrapScratch,
+             in(reg) trap_vector as usize);
+    }
+}
+
+/// Tell the trap code the lowest usable address of the stack this hart is
+/// about to run on (e.g. when switching to a kernel thread)
+#[allow(dead_code)]
+pub fn set_stack_limit(limit: usize) {
+    let hart = cpu_number() as usize;
+    unsafe { TRAP_SCRATCH[hart].stack_limit = limit };
+}
+
+/// Whether this hart is handling an interrupt
+#[allow(dead_code)]
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH[cpu_number() as usize].fetch() != 0
+}
+
+/// Whether this hart takes supervisor interrupts (sstatus.SIE)
+#[allow(dead_code)]
+pub fn interrupts_enabled() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrr {0}, sstatus", out(reg) sstatus) };
+    sstatus & SSTATUS_SIE != 0
+}
+
+// Tell the trap code this hart's HartArea (see hart.rs)
+pub(super) fn set_hart_area(area: usize) {
+    let hart = cpu_number() as usize;
+    unsafe { TRAP_SCRATCH[hart].hart_area = area };
+}
+
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    if frame.scause & CAUSE_INTERRUPT != 0 {
+        let depth = &INTERRUPT_DEPTH[cpu_number() as usize];
+        depth.fetch_add(1);
+        handle_interrupt(frame.scause & !CAUSE_INTERRUPT);
+        depth.fetch_sub(1);
+        return;
+    }
+
+    // trap.S only catches overflows of the current stack; a fault in the
+    // guard of another is still an overflow
+    if is_page_fault(frame.scause) && stack::is_guard_page(frame.stval) {
+        report_overflow(frame);
+    }
+
+    if is_page_fault(frame.scause) {
+        let access = match frame.scause {
+            12 => Access::Execute,
+            13 => Access::Read,
+            _ => Access::Write,
+        };
+        match vm::handle_fault(frame.stval, access) {
+            Ok(()) => return,
+            Err(_) if fixup(frame) => return,
+            Err(e) => {
+                println!("Page fault on hart {}: {} of {:#x} at pc {:#x}: {}",
+                         cpu_number(), access.name(), frame.stval, frame.sepc, e);
+                panic!("Unresolved page fault.\n");
+            }
+        }
+    }
+
+    // An access fault in a user access
+    if is_access_fault(frame.scause) && fixup(frame) {
+        return;
+    }
+
+    println!("Unhandled exception {} at pc {:#x} (stval {:#x}) on hart {}",
+             frame.scause, frame.sepc, frame.stval, cpu_number());
+    panic!("Unhandled exception.\n");
+}
+
+// Dispatch interrupt `cause`.  We don't enable any interrupts yet, so one
+// arriving is a bug: report it, and mask it so it doesn't come straight back.
+fn handle_interrupt(cause: usize) {
+    println!("Unexpected interrupt {} on hart {}", cause, cpu_number());
+    if cause < 64 {
+        unsafe { asm!("csrc sie, {0}", in(reg) 1usize << cause) };
+    }
+}
+
+#[no_mangle]
+extern "C" fn stack_overflow_handler(frame: &mut TrapFrame) -> ! {
+    report_overflow(frame)
+}
+
+fn report_overflow(frame: &TrapFrame) -> ! {
+    println!("Kernel stack overflow on hart {}: sp {:#x}, fault at {:#x}, pc {:#x}",
+             cpu_number(), frame.regs[2], frame.stval, frame.sepc);
+    panic!("Kernel stack overflow.\n");
+}
+
+// Resume a faulting user access at its fixup, if that's what faulted
+fn fixup(frame: &mut TrapFrame) -> bool {
+    match uaccess::search_exception_table(frame.sepc) {
+        Some(pc) => {
+            frame.sepc = pc;
+            true
+        },
+        None => false,
+    }
+}
+
+#[inline(always)]
+fn is_page_fault(scause: usize) -> bool {
+    scause == 12 || scause == 13 || scause == 15
+}
+
+#[inline(always)]
+fn is_access_fault(scause: usize) -> bool {
+    scause == 1 || scause == 5 || scause == 7
+}
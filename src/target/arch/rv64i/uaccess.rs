@@ -0,0 +1,83 @@
+// Copying to and from user memory on behalf of a process (e.g. during a
+// system call).  A bad user pointer gives an error, not a kernel panic.
+//
+// Don't hold the lock of the current address space across these, as a fault
+// on a page not yet touched is resolved (see memory::vm) before carrying on.
+
+use crate::memory::vm;
+
+global_asm!(include_str!("uaccess.S"));
+
+extern "C" {
+    fn __copy_user(dst: usize, src: usize, len: usize) -> usize;
+    fn __strncpy_user(dst: usize, src: usize, max: usize) -> isize;
+
+    static __ex_table_start: ExTableEntry;
+    static __ex_table_end: ExTableEntry;
+}
+
+#[repr(C)]
+struct ExTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UserAccessError {
+    /// The range is not in the user part of the current address space
+    NotUser,
+    /// Part of the range is not mapped, or not accessible
+    Fault,
+}
+
+/// Where to resume after a fault at `pc`, if it was in a user access
+pub fn search_exception_table(pc: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &__ex_table_start as *const ExTableEntry;
+        let end = &__ex_table_end as *const ExTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table.iter().find(|e| e.insn == pc).map(|e| e.fixup)
+}
+
+fn check(va: usize, len: usize) -> Result<(), UserAccessError> {
+    if vm::is_user_range(va, len) {
+        Ok(())
+    } else {
+        Err(UserAccessError::NotUser)
+    }
+}
+
+/// Copy `dst.len()` bytes from user address `src`
+#[allow(dead_code)]
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserAccessError> {
+    check(src, dst.len())?;
+    match unsafe { __copy_user(dst.as_mut_ptr() as usize, src, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Copy `src` to user address `dst`
+#[allow(dead_code)]
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserAccessError> {
+    check(dst, src.len())?;
+    match unsafe { __copy_user(dst, src.as_ptr() as usize, src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Copy a nul terminated string from user address `src` into `dst` (with its
+/// nul, if it fits).  Returns the length of the string, or the number of
+/// bytes copied if no nul was found.
+#[allow(dead_code)]
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize, UserAccessError> {
+    // Only as much as could be read needs to be user memory
+    check(src, 1)?;
+    let max = dst.len().min(vm::user_range_end(src) - src);
+    match unsafe { __strncpy_user(dst.as_mut_ptr() as usize, src, max) } {
+        len if len >= 0 => Ok(len as usize),
+        _ => Err(UserAccessError::Fault),
+    }
+}
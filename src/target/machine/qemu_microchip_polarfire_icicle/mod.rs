@@ -0,0 +1,47 @@
+
+// FIXME
+global_asm!(include_str!("../sifive_hifive_unmatched/boot.S"));
+
+compile_error!("QEMU Microchip PolarFire SoC Icicle Kit will be supported soon, but is not yet.");
+
+/// Highest hart id + 1 (the E51 monitor core plus four U54 cores)
+pub const MAX_HARTS: usize = 5;
+
+#[allow(dead_code)]
+pub const UART0_ADDR: usize = 0x2000_0000;
+
+/// Device registers (base, size), mapped by the kernel page table
+pub const MMIO_REGIONS: &[(usize, usize)] = &[
+    (UART0_ADDR, 0x1000),
+];
+
+/// No L2 cache controller (and so no LIM) that we drive
+pub static CCACHE: Option<crate::device::ccache::SifiveCcache> = None;
+
+/// DMA from the FPGA fabric need not be coherent with the CPU caches
+pub const DMA_COHERENT: bool = false;
+
+// Missing CONSOLE
+
+#[inline(always)]
+pub fn pause() {
+    unsafe {
+        // PAUSE instruction (not yet in llvm backend)
+        llvm_asm!(".word 0x0100000F" : : : : "volatile");
+    }
+}
+
+#[allow(dead_code)]
+#[inline(always)]
+pub fn cease() {
+    unsafe {
+        llvm_asm!(".word 0x30500073" : : : "memory" : "volatile");
+    }
+}
+
+pub fn init() {
+}
+
+pub fn display_machine_information() {
+    println!("Build: QEMU Microchip PolarFire SoC Icicle Kit");
+}
@@ -0,0 +1,46 @@
+
+// FIXME
+global_asm!(include_str!("boot.S"));
+
+use crate::device::uart::uart16550::Uart16550;
+use crate::memory::mmio_phys_to_virt;
+use crate::once::Lazy;
+
+/// Highest hart id + 1 (we run QEMU with -smp 4)
+pub const MAX_HARTS: usize = 4;
+
+#[allow(dead_code)]
+pub const UART0_ADDR: usize = 0x1000_0000;
+
+/// Device registers (base, size), mapped by the kernel page table
+pub const MMIO_REGIONS: &[(usize, usize)] = &[
+    (UART0_ADDR, 0x100),
+];
+
+/// No L2 cache controller (and so no LIM) that we drive
+pub static CCACHE: Option<crate::device::ccache::SifiveCcache> = None;
+
+/// Devices see the CPU caches (no cache maintenance is needed for DMA)
+pub const DMA_COHERENT: bool = true;
+
+pub static CONSOLE: Lazy<Uart16550> = Lazy::new(|| unsafe { Uart16550::new(mmio_phys_to_virt(UART0_ADDR)) });
+
+#[inline(always)]
+pub fn pause() {
+    unsafe {
+        // LLVM does not support (yet?) Zihintpause feature.  Once it does,
+        // we may need a new target json file that enables this processor feature
+        // e.g. "features": "+64bit,+m,+a,+c,+zihintpause",
+	// llvm_asm!("pause"::::"volatile");
+
+        // Until then, we just issue a FENCE
+        asm!("fence");
+    }
+}
+
+pub fn init() {
+}
+
+pub fn display_machine_information() {
+    println!("Build: QEMU virt (riscv64)");
+}
@@ -0,0 +1,82 @@
+
+global_asm!(include_str!("boot.S"));
+
+use crate::device::ccache::SifiveCcache;
+use crate::device::uart::Uart;
+use crate::device::uart::sifive::SifiveUart;
+use crate::memory::mmio_phys_to_virt;
+use crate::once::Lazy;
+
+mod clock;
+
+/// Highest hart id + 1 (the S7 monitor core plus four U74 cores)
+pub const MAX_HARTS: usize = 5;
+
+pub const UART0_ADDR: usize = 0x1001_0000;
+#[allow(dead_code)]
+pub const UART1_ADDR: usize = 0x1001_1000;
+
+pub const CCACHE_ADDR: usize = 0x0201_0000;
+pub const LIM_ADDR: usize = 0x0800_0000;
+pub const LIM_SIZE: usize = 0x0020_0000;
+
+/// Device registers (base, size), mapped by the kernel page table
+pub const MMIO_REGIONS: &[(usize, usize)] = &[
+    (CCACHE_ADDR, 0x1000),
+    (clock::CLOCK_REG_BASE, 0x1000),
+    (UART0_ADDR, 0x1000),
+    (UART1_ADDR, 0x1000),
+];
+
+pub static CONSOLE: Lazy<SifiveUart> = Lazy::new(|| unsafe { SifiveUart::new(mmio_phys_to_virt(UART0_ADDR)) });
+
+pub static CCACHE: Option<SifiveCcache> =
+    Some(unsafe { SifiveCcache::new(mmio_phys_to_virt(CCACHE_ADDR), LIM_ADDR, LIM_SIZE) });
+
+/// DMA goes through the coherent L2 (no cache maintenance is needed)
+pub const DMA_COHERENT: bool = true;
+
+#[inline(always)]
+pub fn pause() {
+    unsafe {
+        // PAUSE instruction (not yet in llvm backend)
+        llvm_asm!(".word 0x0100000F" : : : : "volatile");
+    }
+}
+
+#[allow(dead_code)]
+#[inline(always)]
+pub fn cease() {
+    unsafe {
+        llvm_asm!(".word 0x30500073" : : : "memory" : "volatile");
+    }
+}
+
+pub fn init() {
+}
+
+pub fn display_machine_information() {
+    println!("Build: SiFive HiFive Unmatched");
+
+    println!("Clock Info:");
+    let corefreq = clock::get_core_frequency();
+    println!("  Core frequency = {} Hz", corefreq);
+    println!("  PLL cltx: {}", if clock::prci_plls::get_cltxpll() { "present" } else { "absent" });
+    println!("  PLL gemgxl: {}", if clock::prci_plls::get_gemgxlpll() { "present" } else { "absent" });
+    println!("  PLL ddr: {}", if clock::prci_plls::get_ddrpll() { "present" } else { "absent" });
+    println!("  PLL hfpclk: {}", if clock::prci_plls::get_hfpclkpll() { "present" } else { "absent" });
+    println!("  PLL dvfscore: {}", if clock::prci_plls::get_dvfscorepll() { "present" } else { "absent" });
+    println!("  PLL core: {}", if clock::prci_plls::get_corepll() { "present" } else { "absent" });
+    let tlclk = clock::get_tlclk();
+    println!("  tlclk: {} Hz", tlclk);
+    println!("  UART baud: {}", CONSOLE.get_baud_rate(tlclk as u32));
+
+    if let Some(ccache) = CCACHE.as_ref() {
+        if ccache.is_present() {
+            println!("L2 Cache: {} banks, {} of {} ways enabled, {} KiB per way",
+                     ccache.banks(), ccache.enabled_ways(), ccache.ways(),
+                     ccache.way_size() / 1024);
+        }
+        println!("L2 LIM: {} KiB at {:#x}", ccache.lim_size() / 1024, ccache.lim_address());
+    }
+}
@@ -0,0 +1,6 @@
+
+mod arch;
+pub use arch::*;
+
+mod machine;
+pub use machine::*;